@@ -0,0 +1,423 @@
+use futures::{StreamExt, TryStreamExt};
+use hyper::header::HeaderValue;
+use hyper::{header, Body, Request, Response, StatusCode};
+use url::{form_urlencoded, Url};
+
+use crate::{AppState, GenericError};
+
+/// Upstream response headers that are passed through to the client. The body
+/// is passed through as it is, since reqwest doesn't decompress, so an
+/// encoded body keeps its `Content-Encoding` and matching `Content-Length`.
+const PASSTHROUGH_HEADERS: [header::HeaderName; 7] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+/// Most redirects to follow for one audio file, as for reqwest's default
+const MAX_REDIRECTS: usize = 10;
+
+/// Build the `/audio` URL that proxies `file_url`
+pub fn proxy_url(base: &str, file_url: &str) -> String {
+    let encoded: String = form_urlencoded::byte_serialize(file_url.as_bytes()).collect();
+    format!("{}/audio?url={}", base, encoded)
+}
+
+/// Whether `url` is on a BBC host, which is all `/audio` will fetch from so
+/// that it can't be used as an open proxy
+fn is_bbc_url(url: &Url) -> bool {
+    let is_bbc = url.host_str().is_some_and(|host| {
+        ["bbc.co.uk", "bbci.co.uk"]
+            .iter()
+            .any(|d| host == *d || host.ends_with(&format!(".{}", d)))
+    });
+    is_bbc && matches!(url.scheme(), "http" | "https")
+}
+
+/// Extract the upstream URL from the query string, if it's a BBC one
+fn upstream_url(query: Option<&str>) -> Option<Url> {
+    let (_, raw) = form_urlencoded::parse(query?.as_bytes()).find(|(k, _)| k == "url")?;
+    Url::parse(&raw).ok().filter(is_bbc_url)
+}
+
+/// Follow redirects only to other BBC URLs, so a BBC host can't send the
+/// proxy anywhere else
+pub fn redirect_policy() -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            attempt.error(format!("more than {} redirects", MAX_REDIRECTS))
+        } else if is_bbc_url(attempt.url()) {
+            attempt.follow()
+        } else {
+            let message = format!("redirected away from BBC to {}", attempt.url());
+            attempt.error(message)
+        }
+    })
+}
+
+/// Internal nginx path for `url`, e.g. `/internal-audio/https/host/path?query`
+fn accel_path(location: &str, url: &Url) -> String {
+    let mut path = format!(
+        "{}/{}/{}{}",
+        location.trim_end_matches('/'),
+        url.scheme(),
+        url.host_str().unwrap_or_default(),
+        url.path()
+    );
+    if let Some(q) = url.query() {
+        path.push('?');
+        path.push_str(q);
+    }
+    path
+}
+
+pub async fn get_audio(state: &AppState, req: &Request<Body>) -> Response<Body> {
+    let url = match upstream_url(req.uri().query()) {
+        Some(url) => url,
+        None => return error(StatusCode::BAD_REQUEST, "Missing or invalid url parameter"),
+    };
+
+    if state.config.accel_redirect {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                "X-Accel-Redirect",
+                accel_path(&state.config.accel_redirect_location, &url),
+            )
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    proxy(state, url, req.headers().get(header::RANGE)).await
+}
+
+/// Fetch `url`, or the part of it `range` asks for, from the audio cache if
+/// there is one and from upstream otherwise
+async fn proxy(state: &AppState, url: Url, range: Option<&HeaderValue>) -> Response<Body> {
+    if let Some(cache) = &state.audio_cache {
+        if let Some(cached) = cache.get(&url) {
+            if let Some(response) = cache.serve(&state.audio_client, &url, cached, range).await {
+                return response;
+            }
+        }
+    }
+
+    let mut upstream = state.audio_client.get(url.clone());
+    if let Some(range) = range {
+        upstream = upstream.header(header::RANGE, range.clone());
+    }
+    let resp = match upstream.send().await {
+        Ok(resp) => resp,
+        Err(e) => return error(StatusCode::BAD_GATEWAY, &e.to_string()),
+    };
+    let status = resp.status();
+    match status {
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => {
+            return error(StatusCode::NOT_FOUND, "Audio not found at BBC");
+        }
+        reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+            let mut builder = Response::builder().status(status);
+            if let Some(range) = resp.headers().get(header::CONTENT_RANGE) {
+                builder = builder.header(header::CONTENT_RANGE, range.clone());
+            }
+            return builder.body(Body::empty()).unwrap();
+        }
+        _ if !status.is_success() => {
+            return error(
+                StatusCode::BAD_GATEWAY,
+                &format!("BBC answered {} for the audio", status),
+            );
+        }
+        _ => {}
+    }
+    // An HTML page with a success status is an error page, which a client
+    // would otherwise try to play
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("text/html") {
+        return error(
+            StatusCode::BAD_GATEWAY,
+            "BBC returned an HTML page instead of audio",
+        );
+    }
+    let mut builder = Response::builder().status(status);
+    for name in PASSTHROUGH_HEADERS.iter() {
+        if let Some(value) = resp.headers().get(name) {
+            builder = builder.header(name, value.clone());
+        }
+    }
+    let body = match &state.audio_cache {
+        Some(cache) => cache.pass_through(&url, resp).await,
+        None => resp.bytes_stream().map_err(GenericError::from).boxed(),
+    };
+    builder.body(Body::wrap_stream(body)).unwrap()
+}
+
+/// A plain-text error, so that nothing sent in place of audio has an audio
+/// content type
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_cache::AudioCache;
+    use crate::testing::{self, bytes, tempdir, MockBbc, Raw, Upstream};
+    use std::sync::Arc;
+
+    /// A file whose bytes all differ from their neighbours
+    fn audio_file(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn cached_audio(
+        len: usize,
+        max_bytes: u64,
+    ) -> (MockBbc, Arc<AppState>, std::path::PathBuf) {
+        let mut upstream = Upstream::default();
+        upstream.files.insert("/a.mp3".to_string(), audio_file(len));
+        let bbc = MockBbc::start(upstream).await;
+        let dir = tempdir();
+        let cache = Arc::new(AudioCache::open(&dir, max_bytes).unwrap());
+        let state = testing::state_with_audio_cache(&bbc, &[], Some(cache));
+        (bbc, state, dir)
+    }
+
+    /// Bytes `start..=end` of `url` through the proxy
+    async fn get_range(state: &AppState, url: &str, start: usize, end: usize) -> Vec<u8> {
+        let range = HeaderValue::from_str(&format!("bytes={}-{}", start, end)).unwrap();
+        let response = proxy(state, Url::parse(url).unwrap(), Some(&range)).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        bytes(response).await.to_vec()
+    }
+
+    #[test]
+    fn only_bbc_urls_are_proxied() {
+        let url = |query: &str| upstream_url(Some(query)).map(|u| u.to_string());
+        assert_eq!(
+            url("url=https%3A%2F%2Fopen.live.bbc.co.uk%2Fa.mp3").as_deref(),
+            Some("https://open.live.bbc.co.uk/a.mp3")
+        );
+        assert!(url("url=https%3A%2F%2Fbbc.co.uk.example.com%2Fa.mp3").is_none());
+        assert!(url("url=https%3A%2F%2Fevilbbc.co.uk%2Fa.mp3").is_none());
+        assert!(url("url=file%3A%2F%2F%2Fetc%2Fpasswd").is_none());
+        assert!(url("other=1").is_none());
+    }
+
+    #[tokio::test]
+    async fn audio_redirects_must_stay_on_bbc() {
+        let mut upstream = Upstream::default();
+        upstream.raw.insert(
+            "/a.mp3".to_string(),
+            Raw::redirect("http://127.0.0.1:1/elsewhere.mp3"),
+        );
+        let bbc = MockBbc::start(upstream).await;
+        let client = reqwest::Client::builder()
+            .redirect(redirect_policy())
+            .build()
+            .unwrap();
+        let err = client
+            .get(format!("{}/a.mp3", bbc.url))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_redirect());
+        assert!(err.to_string().contains("redirected away from BBC"));
+    }
+
+    #[tokio::test]
+    async fn cached_ranges_are_not_fetched_again() {
+        let (bbc, state, dir) = cached_audio(300_000, 1_000_000).await;
+        let file = audio_file(300_000);
+        let url = format!("{}/a.mp3?token=1", bbc.url);
+        assert_eq!(get_range(&state, &url, 0, 99_999).await, &file[..100_000]);
+        assert_eq!(bbc.hits("/a.mp3"), 1);
+        assert_eq!(get_range(&state, &url, 0, 99_999).await, &file[..100_000]);
+        assert_eq!(bbc.hits("/a.mp3"), 1);
+
+        // Only the part not yet cached goes upstream, whatever the token
+        let other = format!("{}/a.mp3?token=2", bbc.url);
+        assert_eq!(
+            get_range(&state, &other, 50_000, 149_999).await,
+            &file[50_000..150_000]
+        );
+        assert_eq!(bbc.hits("/a.mp3"), 2);
+        assert_eq!(bbc.hits("bytes=100000-149999"), 1);
+
+        let response = proxy(&state, Url::parse(&url).unwrap(), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(bytes(response).await, &file[..]);
+        assert_eq!(bbc.hits("/a.mp3"), 3);
+        assert_eq!(bbc.hits("bytes=150000-299999"), 1);
+        assert_eq!(
+            get_range(&state, &url, 1000, 250_000).await,
+            &file[1000..=250_000]
+        );
+        assert_eq!(bbc.hits("/a.mp3"), 3);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn evicted_audio_is_fetched_from_upstream() {
+        let (bbc, state, dir) = cached_audio(1000, 1_000_000).await;
+        let url = format!("{}/a.mp3", bbc.url);
+        get_range(&state, &url, 0, 499).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            get_range(&state, &url, 0, 99).await,
+            &audio_file(1000)[..100]
+        );
+        assert_eq!(bbc.hits("/a.mp3"), 2);
+    }
+
+    #[tokio::test]
+    async fn audio_too_big_for_the_cache_is_only_proxied() {
+        let (bbc, state, dir) = cached_audio(1000, 999).await;
+        let url = format!("{}/a.mp3", bbc.url);
+        assert_eq!(
+            get_range(&state, &url, 0, 99).await,
+            &audio_file(1000)[..100]
+        );
+        assert_eq!(
+            get_range(&state, &url, 0, 99).await,
+            &audio_file(1000)[..100]
+        );
+        assert_eq!(bbc.hits("/a.mp3"), 2);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn encoded_audio_is_passed_through_as_it_is() {
+        use std::io::{Read, Write};
+        let audio = audio_file(10_000);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&audio).unwrap();
+        let encoded = encoder.finish().unwrap();
+        let mut upstream = Upstream::default();
+        let mut raw = Raw::new(200, "audio/mpeg", "");
+        raw.body = encoded.clone();
+        raw.headers = vec![("content-encoding", "gzip")];
+        upstream.raw.insert("/a.mp3".to_string(), raw);
+        let bbc = MockBbc::start(upstream).await;
+        let url = Url::parse(&format!("{}/a.mp3", bbc.url)).unwrap();
+        let dir = tempdir();
+        let cache = Arc::new(AudioCache::open(&dir, 1_000_000).unwrap());
+
+        for audio_cache in [None, Some(cache.clone()), Some(cache)] {
+            let cached = audio_cache.is_some();
+            let state = testing::state_with_audio_cache(&bbc, &[], audio_cache);
+            let response = proxy(&state, url.clone(), None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(testing::header(&response, "content-type"), "audio/mpeg");
+            assert_eq!(testing::header(&response, "content-encoding"), "gzip");
+            let length = encoded.len().to_string();
+            assert_eq!(testing::header(&response, "content-length"), length);
+            let body = bytes(response).await;
+            assert_eq!(body, encoded, "cached: {}", cached);
+            let mut decoded = Vec::new();
+            flate2::read::GzDecoder::new(&body[..])
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, audio);
+        }
+        // Encoded bodies aren't the file's bytes, so none were cached
+        assert_eq!(bbc.hits("/a.mp3"), 3);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn audio_errors_are_never_audio() {
+        let mut upstream = Upstream::default();
+        upstream
+            .files
+            .insert("/a.mp3".to_string(), audio_file(1000));
+        let raw = [
+            ("/gone.mp3", Raw::new(410, "text/html", "<html>Gone</html>")),
+            ("/down.mp3", Raw::new(503, "text/html", "<html>Down</html>")),
+            (
+                "/page.mp3",
+                Raw::new(200, "text/html; charset=utf-8", "<html>Oops</html>"),
+            ),
+        ];
+        for (path, raw) in raw {
+            upstream.raw.insert(path.to_string(), raw);
+        }
+        let bbc = MockBbc::start(upstream).await;
+        let state = testing::state(&bbc, &[]);
+        let fetch = |url: String, range: Option<&'static str>| {
+            let state = state.clone();
+            async move {
+                let range = range.map(HeaderValue::from_static);
+                proxy(&state, Url::parse(&url).unwrap(), range.as_ref()).await
+            }
+        };
+        let plain = "text/plain; charset=utf-8";
+        for (path, status) in [
+            ("/missing.mp3", StatusCode::NOT_FOUND),
+            ("/gone.mp3", StatusCode::NOT_FOUND),
+            ("/down.mp3", StatusCode::BAD_GATEWAY),
+            ("/page.mp3", StatusCode::BAD_GATEWAY),
+        ] {
+            let response = fetch(format!("{}{}", bbc.url, path), None).await;
+            assert_eq!(response.status(), status, "{}", path);
+            assert_eq!(
+                testing::header(&response, "content-type"),
+                plain,
+                "{}",
+                path
+            );
+        }
+        let response = fetch("http://127.0.0.1:1/a.mp3".to_string(), None).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(testing::header(&response, "content-type"), plain);
+
+        let response = fetch(format!("{}/a.mp3", bbc.url), Some("bytes=5000-")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(testing::header(&response, "content-range"), "bytes */1000");
+        assert_eq!(testing::header(&response, "content-type"), "");
+        assert!(bytes(response).await.is_empty());
+
+        let response = testing::get(&state, "/audio?url=https%3A%2F%2Fexample.com%2Fa.mp3").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(testing::header(&response, "content-type"), plain);
+    }
+
+    #[tokio::test]
+    async fn accel_redirect_hands_the_upstream_url_to_nginx() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let args = [
+            "--accel-redirect",
+            "--accel-redirect-location",
+            "/audio-internal/",
+        ];
+        let state = testing::state(&bbc, &args);
+        let audio = "/audio?url=https%3A%2F%2Fopen.live.bbc.co.uk%2Fx%2Fa.mp3%3Ftoken%3D1";
+        let response = testing::get(&state, audio).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            testing::header(&response, "x-accel-redirect"),
+            "/audio-internal/https/open.live.bbc.co.uk/x/a.mp3?token=1"
+        );
+        assert!(bytes(response).await.is_empty());
+
+        // URLs that wouldn't be proxied aren't handed over either
+        let response = testing::get(&state, "/audio?url=https%3A%2F%2Fexample.com%2Fa.mp3").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(testing::header(&response, "x-accel-redirect"), "");
+        assert_eq!(bbc.hits(""), 0);
+    }
+}
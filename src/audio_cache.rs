@@ -0,0 +1,753 @@
+use futures::stream::{self, BoxStream, Stream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{header, Body, Response, StatusCode};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore};
+use url::Url;
+
+use crate::range::parse_range;
+use crate::{fnv1a, GenericError};
+
+/// Bytes read from disk per body chunk
+const CHUNK: usize = 64 * 1024;
+
+/// Most files being written to the cache at once. Requests beyond this are
+/// still proxied, just not cached.
+const MAX_FILLS: usize = 4;
+
+/// Used when serving files cached before a restart, whose upstream
+/// `Content-Type` wasn't kept
+const DEFAULT_CONTENT_TYPE: &str = "audio/mpeg";
+
+type ByteStream = BoxStream<'static, Result<Bytes, GenericError>>;
+
+struct Entry {
+    /// Size of the whole file upstream
+    len: u64,
+    /// The inclusive byte ranges on disk, sorted, with none overlapping or
+    /// touching
+    ranges: Vec<(u64, u64)>,
+    /// Value of `Index::clock` when the file was last served
+    last_used: u64,
+    content_type: Option<String>,
+    /// Whether a request is writing to the file, which it alone may do
+    filling: bool,
+}
+
+impl Entry {
+    /// The contents of the file's `.ranges` file: its length, its content
+    /// type and the ranges on disk, one per line
+    fn describe(&self) -> String {
+        let mut out = format!(
+            "{}\n{}\n",
+            self.len,
+            self.content_type.as_deref().unwrap_or_default()
+        );
+        for (start, end) in &self.ranges {
+            out.push_str(&format!("{}-{}\n", start, end));
+        }
+        out
+    }
+}
+
+/// Read a `.ranges` file, as written by `Entry::describe`
+fn parse_description(text: &str) -> Option<Entry> {
+    let mut lines = text.lines();
+    let len: u64 = lines.next()?.parse().ok().filter(|len| *len > 0)?;
+    let content_type = Some(lines.next()?.to_string()).filter(|t| !t.is_empty());
+    let mut ranges = Vec::new();
+    for line in lines {
+        let (start, end) = line.split_once('-')?;
+        let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+        if start > end || end >= len {
+            return None;
+        }
+        insert(&mut ranges, start, end);
+    }
+    Some(Entry {
+        len,
+        ranges,
+        last_used: 0,
+        content_type,
+        filling: false,
+    })
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<u64, Entry>,
+    /// Combined size of the cached files, counting each at its full length
+    /// however much of it is on disk, so the cache can't outgrow its limit
+    /// as the files fill in
+    total: u64,
+    clock: u64,
+}
+
+/// Proxied audio kept on disk, up to a total size, evicting the least
+/// recently served files. Each file is written as clients fetch it, so the
+/// cache holds the byte ranges that have been played. Requests are served
+/// from disk where it has the bytes, going upstream only for the rest.
+pub struct AudioCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<Index>,
+    fills: Arc<Semaphore>,
+}
+
+/// What the cache has of a file, to serve
+pub struct Cached {
+    key: u64,
+    len: u64,
+    ranges: Vec<(u64, u64)>,
+    content_type: String,
+}
+
+/// The key for `url`. BBC's audio URLs carry tokens in their query strings
+/// that don't change the file, so they're left out, or every token would be
+/// a separate copy.
+fn key(url: &Url) -> u64 {
+    let file = format!(
+        "{}://{}{}",
+        url.scheme(),
+        url.host_str().unwrap_or_default(),
+        url.path()
+    );
+    fnv1a(file.as_bytes())
+}
+
+/// Add `start..=end` to `ranges`, merging it with any it overlaps or touches
+fn insert(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    ranges.push((start, end));
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Cached(u64, u64),
+    Missing(u64, u64),
+}
+
+/// Split `start..=end` into the parts `ranges` has and the parts it doesn't,
+/// in order
+fn segments(ranges: &[(u64, u64)], start: u64, end: u64) -> Vec<Segment> {
+    let mut out = Vec::new();
+    let mut pos = start;
+    for &(from, to) in ranges {
+        if to < pos {
+            continue;
+        }
+        if from > end {
+            break;
+        }
+        if from > pos {
+            out.push(Segment::Missing(pos, from - 1));
+        }
+        let until = to.min(end);
+        out.push(Segment::Cached(pos.max(from), until));
+        pos = until + 1;
+        if pos > end {
+            return out;
+        }
+    }
+    out.push(Segment::Missing(pos, end));
+    out
+}
+
+/// The first byte, last byte and length of the file from a `Content-Range`
+/// header like `bytes 0-99/1000`
+fn content_range(value: &HeaderValue) -> Option<(u64, u64, u64)> {
+    let spec = value.to_str().ok()?.strip_prefix("bytes ")?;
+    let (range, len) = spec.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end, len) = (start.parse().ok()?, end.parse().ok()?, len.parse().ok()?);
+    if start > end || end >= len {
+        return None;
+    }
+    Some((start, end, len))
+}
+
+/// Whether a body with `headers` is the file's own bytes. Cached files are
+/// served without a `Content-Encoding`, so only those can be cached.
+fn is_identity(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .is_none_or(|e| e == "identity")
+}
+
+impl AudioCache {
+    /// Open the cache in `dir`, creating it if needed and picking up files
+    /// left by a previous run
+    pub fn open(dir: &Path, max_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let cache = AudioCache {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            index: Mutex::new(Index::default()),
+            fills: Arc::new(Semaphore::new(MAX_FILLS)),
+        };
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let key = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| u64::from_str_radix(s, 16).ok());
+            match (path.extension().and_then(|e| e.to_str()), key) {
+                (Some("audio"), Some(key)) => {
+                    let metadata = std::fs::metadata(&path)?;
+                    match cache.read_entry(key, metadata.len()) {
+                        Some(entry) => found.push((metadata.modified().ok(), key, entry)),
+                        None => cache.remove_files(key),
+                    }
+                }
+                // Left behind by an older version, which downloaded files
+                // whole before renaming them into place
+                (Some("tmp"), _) => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                (Some("ranges"), Some(key)) if !cache.path(key, "audio").exists() => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+        // Treat the most recently written files as the most recently used
+        found.sort_by_key(|(modified, key, _)| (*modified, *key));
+        {
+            let mut index = cache.lock();
+            for (_, key, mut entry) in found {
+                index.clock += 1;
+                index.total += entry.len;
+                entry.last_used = index.clock;
+                index.entries.insert(key, entry);
+            }
+            cache.evict(&mut index, 0);
+        }
+        Ok(cache)
+    }
+
+    /// The entry for the `disk_len`-byte file `key` found on disk. A file
+    /// with no `.ranges` file was written whole, as older versions did.
+    fn read_entry(&self, key: u64, disk_len: u64) -> Option<Entry> {
+        let entry = match std::fs::read_to_string(self.path(key, "ranges")) {
+            Ok(text) => parse_description(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && disk_len > 0 => Entry {
+                len: disk_len,
+                ranges: vec![(0, disk_len - 1)],
+                last_used: 0,
+                content_type: None,
+                filling: false,
+            },
+            Err(_) => return None,
+        };
+        Some(entry).filter(|e| e.len == disk_len)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn path(&self, key: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", key, extension))
+    }
+
+    fn remove_files(&self, key: u64) {
+        let _ = std::fs::remove_file(self.path(key, "audio"));
+        let _ = std::fs::remove_file(self.path(key, "ranges"));
+    }
+
+    /// Drop `key` from the cache, unless a request is writing to it
+    fn remove(&self, index: &mut Index, key: u64) -> bool {
+        match index.entries.get(&key) {
+            Some(entry) if !entry.filling => {
+                index.total -= entry.len;
+                index.entries.remove(&key);
+                self.remove_files(key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// What the cache has of `url`, if anything
+    pub fn get(&self, url: &Url) -> Option<Cached> {
+        let key = key(url);
+        let mut index = self.lock();
+        index.clock += 1;
+        let clock = index.clock;
+        let entry = index.entries.get_mut(&key)?;
+        entry.last_used = clock;
+        Some(Cached {
+            key,
+            len: entry.len,
+            ranges: entry.ranges.clone(),
+            content_type: entry
+                .content_type
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string()),
+        })
+    }
+
+    /// Start writing `url`, `len` bytes of `content_type`, into the cache.
+    /// `None` if it can't be written now: too many files are being written,
+    /// another request is writing this one, or it doesn't fit.
+    async fn fill(
+        self: &Arc<Self>,
+        url: &Url,
+        len: u64,
+        content_type: Option<String>,
+    ) -> Option<Fill> {
+        if len == 0 || len > self.max_bytes {
+            return None;
+        }
+        let permit = self.fills.clone().try_acquire_owned().ok()?;
+        let key = key(url);
+        let created = {
+            let mut index = self.lock();
+            let existing = index.entries.get(&key).map(|e| (e.filling, e.len));
+            let created = match existing {
+                Some((true, _)) => return None,
+                Some((false, cached)) if cached == len => false,
+                // The file has changed upstream, so start it again
+                Some(_) => self.remove(&mut index, key),
+                None => true,
+            };
+            if created {
+                if !self.evict(&mut index, len) {
+                    return None;
+                }
+                index.clock += 1;
+                index.total += len;
+                let last_used = index.clock;
+                index.entries.insert(
+                    key,
+                    Entry {
+                        len,
+                        ranges: Vec::new(),
+                        last_used,
+                        content_type,
+                        filling: true,
+                    },
+                );
+            } else if let Some(entry) = index.entries.get_mut(&key) {
+                entry.filling = true;
+            }
+            created
+        };
+        let path = self.path(key, "audio");
+        let file = async {
+            let file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(created)
+                .open(&path)
+                .await?;
+            if created {
+                file.set_len(len).await?;
+            }
+            Ok::<_, std::io::Error>(file)
+        };
+        let file = match file.await {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("failed to open {} for caching: {}", path.display(), e);
+                let mut index = self.lock();
+                if let Some(entry) = index.entries.get_mut(&key) {
+                    entry.filling = false;
+                }
+                if created {
+                    self.remove(&mut index, key);
+                }
+                return None;
+            }
+        };
+        Some(Fill {
+            cache: self.clone(),
+            key,
+            len,
+            file,
+            broken: false,
+            _permit: permit,
+        })
+    }
+
+    /// Remove the least recently served files until `room` more bytes fit,
+    /// returning whether they do. Files being written are kept.
+    fn evict(&self, index: &mut Index, room: u64) -> bool {
+        while index.total + room > self.max_bytes {
+            let oldest = index
+                .entries
+                .iter()
+                .filter(|(_, e)| !e.filling)
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(&key, _)| key);
+            match oldest {
+                Some(key) => {
+                    self.remove(index, key);
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// The body of `resp`, the upstream response for `url`, written to the
+    /// cache on its way through if it's plain audio whose place in the file
+    /// is known
+    pub async fn pass_through(self: &Arc<Self>, url: &Url, resp: reqwest::Response) -> ByteStream {
+        let headers = resp.headers();
+        let place = match resp.status() {
+            reqwest::StatusCode::OK => headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .map(|len| (0, len)),
+            reqwest::StatusCode::PARTIAL_CONTENT => headers
+                .get(header::CONTENT_RANGE)
+                .and_then(content_range)
+                .map(|(start, _, len)| (start, len)),
+            _ => None,
+        };
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let fill = match place {
+            Some((start, len)) if is_identity(headers) => self
+                .fill(url, len, content_type)
+                .await
+                .map(|fill| (start, fill)),
+            _ => None,
+        };
+        match fill {
+            Some((start, fill)) => tee(
+                resp.bytes_stream(),
+                Arc::new(AsyncMutex::new(Some(fill))),
+                start,
+            ),
+            None => resp.bytes_stream().map_err(GenericError::from).boxed(),
+        }
+    }
+
+    /// Serve `cached`, or the part of it `range` asks for, reading what the
+    /// cache has from disk and fetching the rest from `url`, which is cached
+    /// on its way through. `None` if the file has gone from disk, so the
+    /// caller should proxy `url` as if it weren't cached.
+    pub async fn serve(
+        self: &Arc<Self>,
+        client: &reqwest::Client,
+        url: &Url,
+        cached: Cached,
+        range: Option<&HeaderValue>,
+    ) -> Option<Response<Body>> {
+        let (status, start, end) = match parse_range(range, cached.len) {
+            Ok(Some((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+            Ok(None) => (StatusCode::OK, 0, cached.len - 1),
+            Err(()) => {
+                let response = Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", cached.len))
+                    .body(Body::empty())
+                    .unwrap();
+                return Some(response);
+            }
+        };
+        let segments = segments(&cached.ranges, start, end);
+        let file = if segments.iter().any(|s| matches!(s, Segment::Cached(..))) {
+            let path = self.path(cached.key, "audio");
+            match tokio::fs::File::open(&path).await {
+                Ok(file) => Some(Arc::new(AsyncMutex::new(file))),
+                Err(e) => {
+                    // Evicted since `get`, most likely
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        log::warn!("failed to open cached audio {}: {}", path.display(), e);
+                    }
+                    self.remove(&mut self.lock(), cached.key);
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+        let fill = if segments.iter().any(|s| matches!(s, Segment::Missing(..))) {
+            let content_type = Some(cached.content_type.clone());
+            self.fill(url, cached.len, content_type).await
+        } else {
+            None
+        };
+        let fill = Arc::new(AsyncMutex::new(fill));
+        let (client, url, len) = (client.clone(), url.clone(), cached.len);
+        let body = stream::iter(segments)
+            .map(move |segment| match (segment, &file) {
+                (Segment::Cached(from, to), Some(file)) => read(file.clone(), from, to),
+                (Segment::Cached(from, to), None) | (Segment::Missing(from, to), _) => {
+                    fetch(client.clone(), url.clone(), from, to, len, fill.clone())
+                }
+            })
+            .flatten();
+        let mut builder = Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, cached.content_type)
+            .header(header::CONTENT_LENGTH, end - start + 1)
+            .header(header::ACCEPT_RANGES, "bytes");
+        if status == StatusCode::PARTIAL_CONTENT {
+            builder = builder.header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, cached.len),
+            );
+        }
+        Some(builder.body(Body::wrap_stream(body)).unwrap())
+    }
+}
+
+/// A file being written to the cache. Only one request writes to a file at
+/// a time, and what it has written is recorded when it's done.
+struct Fill {
+    cache: Arc<AudioCache>,
+    key: u64,
+    len: u64,
+    file: tokio::fs::File,
+    /// Set after a failed write, so the rest of the body isn't written
+    broken: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Fill {
+    /// Write `data`, which starts at byte `start`, marking it as cached
+    async fn write(&mut self, start: u64, data: &[u8]) {
+        if self.broken || data.is_empty() {
+            return;
+        }
+        let end = start + data.len() as u64 - 1;
+        if end >= self.len {
+            self.broken = true;
+            return;
+        }
+        let file = &mut self.file;
+        let result = async {
+            file.seek(SeekFrom::Start(start)).await?;
+            file.write_all(data).await?;
+            file.flush().await
+        };
+        match result.await {
+            Ok(()) => {
+                let mut index = self.cache.lock();
+                if let Some(entry) = index.entries.get_mut(&self.key) {
+                    insert(&mut entry.ranges, start, end);
+                }
+            }
+            Err(e) => {
+                log::warn!("failed to write to the audio cache: {}", e);
+                self.broken = true;
+            }
+        }
+    }
+}
+
+impl Drop for Fill {
+    fn drop(&mut self) {
+        let mut index = self.cache.lock();
+        if let Some(entry) = index.entries.get_mut(&self.key) {
+            entry.filling = false;
+            let path = self.cache.path(self.key, "ranges");
+            if let Err(e) = std::fs::write(&path, entry.describe()) {
+                log::warn!("failed to write {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Pass `body`, which starts at byte `start` of the file, through, writing
+/// it to `fill` on the way if there is one
+fn tee(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+    fill: Arc<AsyncMutex<Option<Fill>>>,
+    start: u64,
+) -> ByteStream {
+    body.scan(start, |pos, chunk| {
+        let start = *pos;
+        if let Ok(bytes) = &chunk {
+            *pos += bytes.len() as u64;
+        }
+        futures::future::ready(Some((start, chunk)))
+    })
+    .then(move |(start, chunk)| {
+        let fill = fill.clone();
+        async move {
+            if let (Ok(bytes), Some(fill)) = (&chunk, fill.lock().await.as_mut()) {
+                fill.write(start, bytes).await;
+            }
+            chunk.map_err(GenericError::from)
+        }
+    })
+    .boxed()
+}
+
+/// Bytes `start..=end` of a cached file
+fn read(file: Arc<AsyncMutex<tokio::fs::File>>, start: u64, end: u64) -> ByteStream {
+    stream::unfold(start, move |pos| {
+        let file = file.clone();
+        async move {
+            if pos > end {
+                return None;
+            }
+            let n = (end - pos + 1).min(CHUNK as u64);
+            let mut buf = vec![0; n as usize];
+            let mut file = file.lock().await;
+            let result = async {
+                file.seek(SeekFrom::Start(pos)).await?;
+                file.read_exact(&mut buf).await
+            };
+            match result.await {
+                Ok(_) => Some((Ok(Bytes::from(buf)), pos + n)),
+                Err(e) => Some((Err(e.into()), end + 1)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Bytes `start..=end` of `url`, a `len`-byte file, from upstream, written to
+/// `fill` on the way. An upstream failure ends the body early, since its
+/// status has already been sent.
+fn fetch(
+    client: reqwest::Client,
+    url: Url,
+    start: u64,
+    end: u64,
+    len: u64,
+    fill: Arc<AsyncMutex<Option<Fill>>>,
+) -> ByteStream {
+    stream::once(async move {
+        let resp = client
+            .get(url.clone())
+            .header(header::RANGE, format!("bytes={}-{}", start, end))
+            .send()
+            .await?;
+        let place = resp
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(content_range);
+        let expected = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT
+            && place == Some((start, end, len))
+            && is_identity(resp.headers());
+        if !expected {
+            let message = format!(
+                "upstream answered {} with {:?} for bytes {}-{} of cached {}",
+                resp.status(),
+                place,
+                start,
+                end,
+                url
+            );
+            log::warn!("{}", message);
+            return Err(GenericError::from(message));
+        }
+        Ok(tee(resp.bytes_stream(), fill, start))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tempdir;
+
+    #[test]
+    fn ranges_merge_when_they_overlap_or_touch() {
+        let mut ranges = Vec::new();
+        insert(&mut ranges, 10, 19);
+        insert(&mut ranges, 30, 39);
+        assert_eq!(ranges, vec![(10, 19), (30, 39)]);
+        insert(&mut ranges, 20, 24);
+        assert_eq!(ranges, vec![(10, 24), (30, 39)]);
+        insert(&mut ranges, 0, 100);
+        assert_eq!(ranges, vec![(0, 100)]);
+    }
+
+    #[test]
+    fn requests_split_into_cached_and_missing_parts() {
+        use Segment::*;
+        let ranges = [(10, 19), (30, 39)];
+        assert_eq!(segments(&ranges, 0, 5), vec![Missing(0, 5)]);
+        assert_eq!(segments(&ranges, 12, 15), vec![Cached(12, 15)]);
+        assert_eq!(
+            segments(&ranges, 0, 49),
+            vec![
+                Missing(0, 9),
+                Cached(10, 19),
+                Missing(20, 29),
+                Cached(30, 39),
+                Missing(40, 49)
+            ]
+        );
+        assert_eq!(
+            segments(&ranges, 15, 35),
+            vec![Cached(15, 19), Missing(20, 29), Cached(30, 35)]
+        );
+        assert_eq!(segments(&[], 0, 9), vec![Missing(0, 9)]);
+    }
+
+    #[test]
+    fn query_strings_share_a_file() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(
+            key(&url("https://open.live.bbc.co.uk/a.mp3?token=1")),
+            key(&url("https://open.live.bbc.co.uk/a.mp3?token=2"))
+        );
+        assert_ne!(
+            key(&url("https://open.live.bbc.co.uk/a.mp3")),
+            key(&url("https://open.live.bbc.co.uk/b.mp3"))
+        );
+    }
+
+    #[test]
+    fn descriptions_round_trip() {
+        let entry = Entry {
+            len: 100,
+            ranges: vec![(0, 9), (50, 99)],
+            last_used: 0,
+            content_type: Some("audio/mpeg".to_string()),
+            filling: false,
+        };
+        let parsed = parse_description(&entry.describe()).unwrap();
+        assert_eq!(parsed.describe(), entry.describe());
+        assert!(parse_description("100\n\n0-100\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn fills_are_one_per_file_and_bounded() {
+        let dir = tempdir();
+        let cache = Arc::new(AudioCache::open(&dir, 1_000_000).unwrap());
+        let url = |n: usize| Url::parse(&format!("https://open.live.bbc.co.uk/{}.mp3", n)).unwrap();
+        let first = cache.fill(&url(0), 1000, None).await;
+        assert!(first.is_some());
+        assert!(cache.fill(&url(0), 1000, None).await.is_none());
+        let others: Vec<_> = futures::future::join_all((1..MAX_FILLS).map(|n| {
+            let (cache, url) = (cache.clone(), url(n));
+            async move { cache.fill(&url, 1000, None).await }
+        }))
+        .await;
+        assert!(others.iter().all(Option::is_some));
+        assert!(cache.fill(&url(MAX_FILLS), 1000, None).await.is_none());
+        drop(first);
+        assert!(cache.fill(&url(MAX_FILLS), 1000, None).await.is_some());
+        // Too big to fit even once everything else is evicted
+        assert!(cache.fill(&url(99), 1_000_001, None).await.is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,645 @@
+use chrono::{DateTime, FixedOffset};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::cache::CachePolicy;
+use crate::error::ProxyError;
+use crate::AppState;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodContainer {
+    pub titles: PodTitles,
+    pub synopses: PodSynopses,
+    pub image_url: String,
+    #[serde(default)]
+    pub contributors: Vec<PodContributor>,
+    /// The programme ID BBC redirected the requested one to, if it did
+    #[serde(skip)]
+    pub canonical_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodContributor {
+    pub name: String,
+    #[serde(
+        default,
+        deserialize_with = "serde_with::rust::default_on_null::deserialize"
+    )]
+    pub role: String,
+}
+
+impl PodContributor {
+    pub fn is_presenter(&self) -> bool {
+        ["presenter", "host"]
+            .iter()
+            .any(|r| self.role.eq_ignore_ascii_case(r))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodSynopses {
+    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
+    pub short: String,
+    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
+    pub medium: String,
+    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
+    pub long: String,
+}
+
+impl PodSynopses {
+    pub fn is_empty(&self) -> bool {
+        self.short.is_empty() && self.medium.is_empty() && self.long.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PodEpisodes {
+    pub data: Vec<PodEpisode>,
+    /// Number of episodes across all pages
+    #[serde(default)]
+    pub total: usize,
+    /// Set when some pages failed to load and `data` is incomplete
+    #[serde(skip)]
+    pub partial: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodEpisode {
+    /// The episode PID
+    #[serde(default)]
+    pub id: String,
+    pub titles: PodTitles,
+    pub synopses: PodSynopses,
+    pub image_url: String,
+    pub duration: PodDuration,
+    pub download: PodDownload,
+    pub release: PodRelease,
+    #[serde(default)]
+    pub contributors: Vec<PodContributor>,
+    #[serde(default)]
+    pub availability: Option<PodAvailability>,
+    /// 1-based position in release order, oldest first, assigned by us
+    #[serde(skip)]
+    pub number: usize,
+}
+
+impl PodEpisode {
+    /// The release date, if BBC gave a valid one
+    pub fn release_date(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.release.date).ok()
+    }
+
+    /// When the episode stops being available, if BBC gave an end date
+    pub fn available_until(&self) -> Option<DateTime<FixedOffset>> {
+        let to = self.availability.as_ref()?.to.as_deref()?;
+        DateTime::parse_from_rfc3339(to).ok()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodAvailability {
+    #[serde(default)]
+    pub from: Option<String>,
+    #[serde(default)]
+    pub to: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodTitles {
+    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
+    pub primary: String,
+    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
+    pub secondary: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodDuration {
+    pub value: u64,
+    pub label: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodDownload {
+    #[serde(rename = "type")]
+    pub download_type: String, // "non-drm"
+    pub quality_variants: PodQualityVariants,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodQualityVariants {
+    pub low: PodQualityVariant,
+    pub medium: PodQualityVariant,
+    pub high: PodQualityVariant,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodQualityVariant {
+    pub bitrate: u32,
+    pub file_url: String,
+    pub file_size: u32,
+    pub label: String,
+}
+
+impl PodQualityVariant {
+    /// The size of `seconds` of audio at this variant's bitrate, which BBC
+    /// gives in kbit/s
+    pub fn estimated_size(&self, seconds: u64) -> u64 {
+        seconds * u64::from(self.bitrate) * 1000 / 8
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodRelease {
+    pub date: String,
+    pub label: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodError {
+    pub id: String,
+    pub href: String,
+    pub status: u32,
+    pub message: String,
+    pub replied_at: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PodErrors {
+    pub errors: Vec<PodError>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PodContainerResponse {
+    Success(PodContainer),
+    Failure(PodErrors),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PodEpisodesResponse {
+    Success(PodEpisodes),
+    Failure(PodErrors),
+}
+
+/// Whether a `Content-Type` is JSON, such as `application/json` or
+/// `application/problem+json`
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("application/json")
+        || essence.to_ascii_lowercase().ends_with("+json")
+}
+
+/// Most bytes of an unexpected response body to log
+const SNIPPET_LEN: usize = 200;
+
+/// Fetch and decode a JSON response, along with the URL it came from after
+/// any redirects. With `retry_decode`, a response that can't be decoded is
+/// fetched once more, since a body cut short looks the same as one that
+/// doesn't match what we expect.
+async fn get_json<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+    retry_decode: bool,
+) -> Result<(T, reqwest::Url), ProxyError> {
+    match fetch_json(client, url).await {
+        Err(ProxyError::Decode(e)) if retry_decode => {
+            warn!(
+                "retrying {} after a response that couldn't be decoded: {}",
+                url, e
+            );
+            fetch_json(client, url).await
+        }
+        result => result,
+    }
+}
+
+/// Fetch and decode a JSON response, telling an empty body or one that isn't
+/// JSON at all apart from other decoding failures. A missing `Content-Type`
+/// is given the benefit of the doubt, and a body that isn't JSON with an
+/// error status is reported as that status.
+async fn fetch_json<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<(T, reqwest::Url), ProxyError> {
+    let response = client.get(url).send().await?;
+    let final_url = response.url().clone();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+    // BBC's "not found" comes as JSON with an error status, so that's decoded
+    let status_error = response.error_for_status_ref().err();
+    let body = response.bytes().await?;
+    if body.is_empty() {
+        warn!("empty response from BBC for {}", url);
+        return Err(ProxyError::EmptyResponse);
+    }
+    if let Some(content_type) = content_type.filter(|t| !is_json(t)) {
+        warn!(
+            "{} response from BBC for {}: {:?}",
+            content_type,
+            url,
+            String::from_utf8_lossy(&body[..body.len().min(SNIPPET_LEN)])
+        );
+        return Err(match status_error {
+            Some(e) => e.into(),
+            None => ProxyError::UnexpectedContentType(content_type),
+        });
+    }
+    Ok((serde_json::from_slice(&body)?, final_url))
+}
+
+async fn get_pod_info(
+    client: &reqwest::Client,
+    base: &str,
+    id: &str,
+    retry_decode: bool,
+) -> Result<PodContainerResponse, ProxyError> {
+    let url = format!("{}/programmes/{}/container", base, id);
+    let (mut response, final_url) = get_json(client, &url, retry_decode).await?;
+    // A redirect to the container of another programme ID means BBC has
+    // merged this one into it
+    if let PodContainerResponse::Success(info) = &mut response {
+        let mut segments = final_url.path_segments().into_iter().flatten().rev();
+        if let (Some("container"), Some(canonical)) = (segments.next(), segments.next()) {
+            if canonical != id {
+                info!("BBC redirected programme {} to {}", id, canonical);
+                info.canonical_id = Some(canonical.to_string());
+            }
+        }
+    }
+    Ok(response)
+}
+
+async fn get_pod_episodes(
+    client: &reqwest::Client,
+    base: &str,
+    id: &str,
+    offset: usize,
+    retry_decode: bool,
+) -> Result<PodEpisodesResponse, ProxyError> {
+    let url = format!(
+        "{}/programmes/playable?container={}&sort=sequential&type=episode&experience=domestic&offset={}",
+        base, id, offset
+    );
+    let (response, _) = get_json(client, &url, retry_decode).await?;
+    Ok(response)
+}
+
+fn not_found(err: PodErrors) -> ProxyError {
+    let message = err.errors.into_iter().next().map(|e| e.message);
+    ProxyError::NotFound(message.unwrap_or_else(|| "Programme not found".to_string()))
+}
+
+/// Fetch one page of `id`'s episodes, starting at `offset`
+async fn get_page(
+    client: &reqwest::Client,
+    base: &str,
+    id: &str,
+    offset: usize,
+    retry_decode: bool,
+) -> Result<PodEpisodes, ProxyError> {
+    match get_pod_episodes(client, base, id, offset, retry_decode).await? {
+        PodEpisodesResponse::Success(page) => Ok(page),
+        PodEpisodesResponse::Failure(err) => Err(not_found(err)),
+    }
+}
+
+/// Fetch every page of `id`'s episodes. If a page after the first fails and
+/// `partial` is set, return the episodes collected so far marked as partial.
+async fn get_all_episodes(
+    client: &reqwest::Client,
+    base: &str,
+    id: &str,
+    partial: bool,
+    retry_decode: bool,
+) -> Result<PodEpisodes, ProxyError> {
+    let mut episodes = get_page(client, base, id, 0, retry_decode).await?;
+    while episodes.data.len() < episodes.total {
+        let offset = episodes.data.len();
+        match get_page(client, base, id, offset, retry_decode).await {
+            Ok(page) if page.data.is_empty() => break,
+            Ok(mut page) => episodes.data.append(&mut page.data),
+            Err(e) if partial => {
+                warn!(
+                    "episodes of {} at offset {} failed, serving {} of {}: {}",
+                    id, offset, offset, episodes.total, e
+                );
+                episodes.partial = true;
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(episodes)
+}
+
+/// One page of `id`'s episodes starting at `offset`, uncached, for feeds
+/// that are streamed a page at a time
+pub async fn episodes_page(
+    state: &AppState,
+    id: &str,
+    offset: usize,
+) -> Result<PodEpisodes, ProxyError> {
+    let config = &state.config;
+    get_page(
+        &state.client,
+        &config.api_url(),
+        id,
+        offset,
+        config.retry_on_decode_error,
+    )
+    .await
+}
+
+/// Container metadata for `id`, cached for `--container-cache-ttl` since it
+/// rarely changes
+pub async fn container(
+    state: &AppState,
+    id: &str,
+    policy: CachePolicy,
+) -> Result<Arc<PodContainer>, ProxyError> {
+    let client = state.client.clone();
+    let base = state.config.api_url();
+    let owned_id = id.to_string();
+    let retry_decode = state.config.retry_on_decode_error;
+    let (result, _) = state
+        .containers
+        .get_or_fetch(id, policy, || async move {
+            match get_pod_info(&client, &base, &owned_id, retry_decode).await? {
+                PodContainerResponse::Success(info) => Ok(Arc::new(info)),
+                PodContainerResponse::Failure(err) => Err(not_found(err)),
+            }
+        })
+        .await;
+    result
+}
+
+/// Episode list for `id`, cached for `--cache-ttl`
+pub async fn episodes(
+    state: &AppState,
+    id: &str,
+    policy: CachePolicy,
+) -> Result<Arc<PodEpisodes>, ProxyError> {
+    let client = state.client.clone();
+    let base = state.config.api_url();
+    let owned_id = id.to_string();
+    let partial = state.config.partial_on_pagination_error;
+    let retry_decode = state.config.retry_on_decode_error;
+    let (result, _) = state
+        .episodes
+        .get_or_fetch(id, policy, || async move {
+            get_all_episodes(&client, &base, &owned_id, partial, retry_decode)
+                .await
+                .map(Arc::new)
+        })
+        .await;
+    result
+}
+
+/// Fetch a known programme, bypassing the caches, to check that the BBC API
+/// is reachable and its responses still deserialize
+pub async fn self_check(state: &AppState, id: &str) -> Result<(), ProxyError> {
+    let base = &state.config.api_url();
+    let retry_decode = state.config.retry_on_decode_error;
+    let info = get_pod_info(&state.client, base, id, retry_decode).await?;
+    if let PodContainerResponse::Failure(err) = info {
+        return Err(not_found(err));
+    }
+    let episodes = get_pod_episodes(&state.client, base, id, 0, retry_decode).await?;
+    if let PodEpisodesResponse::Failure(err) = episodes {
+        return Err(not_found(err));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use crate::error::ProxyError;
+    use crate::testing::{self, get, header, MockBbc, Raw, Upstream, PROGRAMME};
+
+    /// A mock where `p0old001`'s container redirects to `PROGRAMME`'s via
+    /// `hops` redirects
+    async fn redirecting(hops: usize) -> MockBbc {
+        let mut upstream = Upstream::default();
+        let target = format!("/v2/programmes/{}/container", PROGRAMME);
+        for hop in 0..hops {
+            let from = match hop {
+                0 => "/v2/programmes/p0old001/container".to_string(),
+                _ => format!("/hop/{}", hop),
+            };
+            let to = match hop + 1 {
+                next if next == hops => target.clone(),
+                next => format!("/hop/{}", next),
+            };
+            upstream.raw.insert(from, Raw::redirect(&to));
+        }
+        MockBbc::start(upstream).await
+    }
+
+    #[tokio::test]
+    async fn redirected_programme_is_served_under_its_canonical_id() {
+        let bbc = redirecting(1).await;
+        let state = testing::state(&bbc, &["--canonical-id-header"]);
+        let response = get(&state, "/p0old001").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "x-canonical-id"), PROGRAMME);
+        assert_eq!(bbc.hits(&format!("container={}", PROGRAMME)), 1);
+    }
+
+    #[tokio::test]
+    async fn redirects_are_limited() {
+        let bbc = redirecting(3).await;
+        for (max, followed) in [("0", false), ("2", false), ("3", true)] {
+            let state = testing::state(&bbc, &["--max-redirects", max]);
+            let response = get(&state, "/p0old001").await;
+            assert_eq!(
+                response.status().is_success(),
+                followed,
+                "--max-redirects {}",
+                max
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn container_is_fetched_once_while_episodes_are_refreshed() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--cache-ttl", "0"]);
+        let path = format!("/{}", PROGRAMME);
+        for _ in 0..3 {
+            assert_eq!(get(&state, &path).await.status(), StatusCode::OK);
+        }
+        assert_eq!(bbc.hits("/container"), 1);
+        assert_eq!(bbc.hits("/playable"), 3);
+    }
+
+    #[tokio::test]
+    async fn self_check_needs_both_responses_to_deserialize() {
+        let mut upstream = Upstream::default();
+        upstream.raw.insert(
+            "/v2/programmes/p0broken/container".to_string(),
+            Raw::new(200, "application/json", "{\"titles\": 1}"),
+        );
+        let bbc = MockBbc::start(upstream).await;
+        let state = testing::state(&bbc, &[]);
+        assert!(super::self_check(&state, PROGRAMME).await.is_ok());
+        assert!(matches!(
+            super::self_check(&state, "p0000404").await,
+            Err(ProxyError::NotFound(_))
+        ));
+        assert!(super::self_check(&state, "p0broken").await.is_err());
+        bbc.set_failing(true);
+        assert!(super::self_check(&state, PROGRAMME).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_later_page_gives_a_partial_feed_unless_turned_off() {
+        let bbc = MockBbc::start(Upstream {
+            episodes: (1..=5).map(testing::episode_json).collect(),
+            page_size: 2,
+            fail_from_offset: Some(2),
+            ..Upstream::default()
+        })
+        .await;
+        let path = format!("/{}", PROGRAMME);
+        let state = testing::state(&bbc, &["--partial-on-pagination-error", "false"]);
+        let response = get(&state, &path).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let response = get(&testing::state(&bbc, &[]), &path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "x-partial"), "true");
+        let feed = testing::text(response).await;
+        assert_eq!(feed.matches("<item>").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn empty_bbc_response_is_a_bad_gateway_after_one_retry() {
+        let mut upstream = Upstream::default();
+        let container = format!("/v2/programmes/{}/container", PROGRAMME);
+        upstream
+            .raw
+            .insert(container, Raw::new(200, "application/json", ""));
+        let bbc = MockBbc::start(upstream).await;
+        let state = testing::state(&bbc, &[]);
+        let response = get(&state, &format!("/{}", PROGRAMME)).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(bbc.hits("/container"), 2);
+        let error = super::container(&state, PROGRAMME, Default::default()).await;
+        assert!(matches!(error, Err(ProxyError::EmptyResponse)));
+    }
+
+    #[tokio::test]
+    async fn html_from_bbc_is_a_bad_gateway() {
+        let mut upstream = Upstream::default();
+        let container = format!("/v2/programmes/{}/container", PROGRAMME);
+        let page = "<html><body>Sorry, we're having problems</body></html>";
+        upstream
+            .raw
+            .insert(container, Raw::new(200, "text/html; charset=utf-8", page));
+        let bbc = MockBbc::start(upstream).await;
+        let state = testing::state(&bbc, &[]);
+        let response = get(&state, &format!("/{}", PROGRAMME)).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = testing::text(response).await;
+        assert!(
+            body.contains("text/html; charset=utf-8 instead of JSON"),
+            "{}",
+            body
+        );
+        let error = super::container(&state, PROGRAMME, Default::default()).await;
+        assert!(matches!(
+            error,
+            Err(ProxyError::UnexpectedContentType(t)) if t == "text/html; charset=utf-8"
+        ));
+    }
+
+    #[test]
+    fn json_content_types() {
+        for json in [
+            "application/json",
+            "Application/JSON; charset=utf-8",
+            "application/problem+json",
+        ] {
+            assert!(super::is_json(json), "{}", json);
+        }
+        for other in [
+            "text/html",
+            "text/plain; charset=utf-8",
+            "application/jsonp",
+        ] {
+            assert!(!super::is_json(other), "{}", other);
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_never_stop_later_requests_reaching_bbc() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        // Unknown programmes are each asked about once, and go on being
+        // asked about
+        for _ in 0..20 {
+            let response = get(&state, "/p0000404").await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(bbc.hits("/p0000404/container"), 20);
+        let feed = format!("/{}", PROGRAMME);
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::OK);
+
+        // Nor does a run of BBC errors keep requests from it once it recovers
+        let state = testing::state(&bbc, &[]);
+        bbc.set_failing(true);
+        for _ in 0..20 {
+            let response = get(&state, &feed).await;
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        }
+        bbc.set_failing(false);
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn bbc_cache_control_does_not_extend_the_cache_ttl() {
+        let mut upstream = Upstream::default();
+        let mut container = Raw::new(200, "application/json", &upstream.container.to_string());
+        container.headers = vec![("cache-control", "public, max-age=86400")];
+        upstream
+            .raw
+            .insert(format!("/v2/programmes/{}/container", PROGRAMME), container);
+        let bbc = MockBbc::start(upstream).await;
+        let args = ["--cache-ttl", "1", "--container-cache-ttl", "1"];
+        let state = testing::state(&bbc, &args);
+        let feed = format!("/{}", PROGRAMME);
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::OK);
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::OK);
+        assert_eq!(bbc.hits("/container"), 2);
+    }
+
+    #[tokio::test]
+    async fn undecodable_response_is_fetched_once_more_unless_turned_off() {
+        let mut upstream = Upstream::default();
+        let container = format!("/v2/programmes/{}/container", PROGRAMME);
+        let cut_short = r#"{"version": "1.0", "titles": {"prim"#;
+        upstream
+            .raw
+            .insert(container, Raw::new(200, "application/json", cut_short));
+        let bbc = MockBbc::start(upstream).await;
+        let feed = format!("/{}", PROGRAMME);
+        let state = testing::state(&bbc, &[]);
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(bbc.hits("/container"), 2);
+        let error = super::container(&state, PROGRAMME, Default::default()).await;
+        assert!(matches!(error, Err(ProxyError::Decode(_))));
+
+        let args = ["--retry-on-decode-error", "false"];
+        let state = testing::state(&bbc, &args);
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(bbc.hits("/container"), 5);
+    }
+}
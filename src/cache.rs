@@ -0,0 +1,332 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::error::ProxyError;
+
+type SharedFetch<V> = Shared<BoxFuture<'static, Result<V, ProxyError>>>;
+
+/// How a value returned by [`Cache::get_or_fetch`] was obtained
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Served from a fresh cache entry
+    Hit,
+    /// Fetched by this request
+    Miss,
+    /// Waited on another request's in-flight fetch for the same key
+    Coalesced,
+    /// Served from an expired entry because fetching a fresh value failed
+    Stale,
+}
+
+/// Whether a lookup may use and update cached values, from the request's
+/// `Cache-Control` header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    #[default]
+    Normal,
+    /// Ignore any cached value, but store the fresh one (`no-cache`)
+    Refresh,
+    /// Neither read nor store cached values (`no-store`)
+    NoStore,
+}
+
+/// How a value was obtained, and how old it is
+#[derive(Clone, Copy, Debug)]
+pub struct CacheInfo {
+    pub status: CacheStatus,
+    pub age: Duration,
+}
+
+struct Entry<V> {
+    value: V,
+    stored: Instant,
+}
+
+struct State<V> {
+    entries: HashMap<String, Entry<V>>,
+    in_flight: HashMap<String, SharedFetch<V>>,
+}
+
+/// Lock the cache state. Every update is made under a single lock, so the
+/// state is consistent even if a panic poisoned the mutex.
+fn lock<V>(state: &Mutex<State<V>>) -> MutexGuard<'_, State<V>> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Removes a key's in-flight fetch if the fetch panics, so that later
+/// requests for the key start a new fetch rather than waiting on a dead one
+struct InFlightGuard<V> {
+    state: Arc<Mutex<State<V>>>,
+    key: Option<String>,
+}
+
+impl<V> Drop for InFlightGuard<V> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            lock(&self.state).in_flight.remove(&key);
+        }
+    }
+}
+
+/// TTL cache with single-flight fetching: concurrent misses for the same key
+/// share one upstream fetch rather than each making their own. Entries are
+/// kept for a further `stale` period after expiring, to be served if
+/// fetching a fresh value fails.
+pub struct Cache<V> {
+    ttl: Duration,
+    stale: Duration,
+    state: Arc<Mutex<State<V>>>,
+}
+
+impl<V: Clone + Send + Sync + 'static> Cache<V> {
+    pub fn new(ttl: Duration, stale: Duration) -> Self {
+        Cache {
+            ttl,
+            stale,
+            state: Arc::new(Mutex::new(State {
+                entries: HashMap::new(),
+                in_flight: HashMap::new(),
+            })),
+        }
+    }
+
+    pub async fn get_or_fetch<F, Fut>(
+        &self,
+        key: &str,
+        policy: CachePolicy,
+        fetch: F,
+    ) -> (Result<V, ProxyError>, CacheInfo)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, ProxyError>> + Send + 'static,
+    {
+        if policy == CachePolicy::NoStore {
+            // Run the fetch here rather than on its own task, as there is
+            // nothing to store if the request goes away
+            let in_flight = lock(&self.state).in_flight.get(key).cloned();
+            let (result, status) = match in_flight {
+                Some(shared) => (shared.await, CacheStatus::Coalesced),
+                None => (fetch().await, CacheStatus::Miss),
+            };
+            let info = CacheInfo {
+                status,
+                age: Duration::ZERO,
+            };
+            return (result, info);
+        }
+        let (shared, status, stale) = {
+            let mut state = lock(&self.state);
+            let mut stale = None;
+            let cached = state.entries.get(key);
+            if let Some(entry) = cached.filter(|_| policy == CachePolicy::Normal) {
+                let age = entry.stored.elapsed();
+                let info = |status| CacheInfo { status, age };
+                if age < self.ttl {
+                    return (Ok(entry.value.clone()), info(CacheStatus::Hit));
+                }
+                if age < self.ttl + self.stale {
+                    stale = Some((entry.value.clone(), entry.stored));
+                }
+            }
+            match state.in_flight.get(key) {
+                Some(shared) => {
+                    log::debug!("coalescing request for {} onto in-flight fetch", key);
+                    (shared.clone(), CacheStatus::Coalesced, stale)
+                }
+                None => {
+                    let shared = self.spawn_fetch(key.to_string(), fetch());
+                    state.in_flight.insert(key.to_string(), shared.clone());
+                    (shared, CacheStatus::Miss, stale)
+                }
+            }
+        };
+        match (shared.await, stale) {
+            (Err(e), Some((value, stored))) => {
+                log::warn!("serving stale {} after fetch failed: {}", key, e);
+                let info = CacheInfo {
+                    status: CacheStatus::Stale,
+                    age: stored.elapsed(),
+                };
+                (Ok(value), info)
+            }
+            (result, _) => {
+                let info = CacheInfo {
+                    status,
+                    age: Duration::ZERO,
+                };
+                (result, info)
+            }
+        }
+    }
+
+    /// The cached value for `key`, fresh or stale, without fetching
+    pub fn peek(&self, key: &str) -> Option<(V, CacheInfo)> {
+        let state = lock(&self.state);
+        let entry = state.entries.get(key)?;
+        let age = entry.stored.elapsed();
+        let status = if age < self.ttl {
+            CacheStatus::Hit
+        } else if age < self.ttl + self.stale {
+            CacheStatus::Stale
+        } else {
+            return None;
+        };
+        Some((entry.value.clone(), CacheInfo { status, age }))
+    }
+
+    /// Wait for the fetches in flight to finish and store their results,
+    /// returning how many there were
+    pub async fn settle(&self) -> usize {
+        let fetches: Vec<SharedFetch<V>> = lock(&self.state).in_flight.values().cloned().collect();
+        let count = fetches.len();
+        futures::future::join_all(fetches).await;
+        count
+    }
+
+    /// Run the fetch on its own task so that it completes, and the result is
+    /// stored, even if the request that started it goes away
+    fn spawn_fetch<Fut>(&self, key: String, fetch: Fut) -> SharedFetch<V>
+    where
+        Fut: Future<Output = Result<V, ProxyError>> + Send + 'static,
+    {
+        let state = self.state.clone();
+        let keep = self.ttl + self.stale;
+        let task = tokio::spawn(async move {
+            let mut guard = InFlightGuard {
+                state: state.clone(),
+                key: Some(key.clone()),
+            };
+            let result = fetch.await;
+            // Clear the in-flight fetch and store its result in one update,
+            // so a request always finds one or the other
+            let mut state = lock(&state);
+            guard.key = None;
+            state.in_flight.remove(&key);
+            state.entries.retain(|_, e| e.stored.elapsed() < keep);
+            if let Ok(value) = &result {
+                let entry = Entry {
+                    value: value.clone(),
+                    stored: Instant::now(),
+                };
+                state.entries.insert(key, entry);
+            }
+            result
+        });
+        // Every request waiting on the fetch gets the error, but the shared
+        // future only runs once, so it's only logged once
+        async move {
+            task.await.unwrap_or_else(|e| {
+                log::error!("cache fetch task failed: {}", e);
+                Err(ProxyError::FetchPanicked)
+            })
+        }
+        .boxed()
+        .shared()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache() -> Cache<u32> {
+        Cache::new(Duration::from_secs(60), Duration::ZERO)
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_fetch() {
+        let cache = cache();
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let lookups = (0..800).map(|_| {
+            let fetches = fetches.clone();
+            cache.get_or_fetch("key", CachePolicy::Normal, move || async move {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(7)
+            })
+        });
+        let results = futures::future::join_all(lookups).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|(result, _)| matches!(result, Ok(7))));
+        let misses = results
+            .iter()
+            .filter(|(_, info)| info.status == CacheStatus::Miss)
+            .count();
+        assert_eq!(misses, 1);
+    }
+
+    #[tokio::test]
+    async fn panicking_fetch_is_an_error_for_every_waiter() {
+        let cache = cache();
+        let (panic_tx, panic_rx) = tokio::sync::oneshot::channel::<()>();
+        let first = cache.get_or_fetch("key", CachePolicy::Normal, || async move {
+            let _ = panic_rx.await;
+            panic!("fetch failed");
+        });
+        tokio::pin!(first);
+        // Start the fetch, then join it, before letting it panic
+        assert!(futures::poll!(&mut first).is_pending());
+        let second = cache.get_or_fetch("key", CachePolicy::Normal, || async { Ok(1) });
+        tokio::pin!(second);
+        assert!(futures::poll!(&mut second).is_pending());
+        panic_tx.send(()).unwrap();
+
+        let ((first, _), (second, info)) = tokio::join!(first, second);
+        assert!(matches!(first, Err(ProxyError::FetchPanicked)));
+        assert!(matches!(second, Err(ProxyError::FetchPanicked)));
+        assert_eq!(info.status, CacheStatus::Coalesced);
+
+        // The dead fetch doesn't stay in flight
+        let (result, info) = cache
+            .get_or_fetch("key", CachePolicy::Normal, || async { Ok(2) })
+            .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(info.status, CacheStatus::Miss);
+    }
+
+    #[tokio::test]
+    async fn refresh_skips_the_cached_value_but_stores_the_new_one() {
+        let cache = cache();
+        let get =
+            |policy, value| cache.get_or_fetch("key", policy, move || async move { Ok(value) });
+        assert_eq!(get(CachePolicy::Normal, 1).await.0.unwrap(), 1);
+
+        let (result, info) = get(CachePolicy::Refresh, 2).await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(info.status, CacheStatus::Miss);
+        let (result, info) = get(CachePolicy::Normal, 3).await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(info.status, CacheStatus::Hit);
+
+        let (result, info) = get(CachePolicy::NoStore, 4).await;
+        assert_eq!(result.unwrap(), 4);
+        assert_eq!(info.status, CacheStatus::Miss);
+        assert_eq!(get(CachePolicy::Normal, 5).await.0.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn peek_sees_fresh_and_stale_values_without_fetching() {
+        let cache = Cache::new(Duration::from_millis(100), Duration::from_millis(100));
+        assert!(cache.peek("key").is_none());
+        let fetch = || async { Ok(1) };
+        assert_eq!(
+            cache
+                .get_or_fetch("key", CachePolicy::Normal, fetch)
+                .await
+                .0
+                .unwrap(),
+            1
+        );
+        let (value, info) = cache.peek("key").unwrap();
+        assert_eq!((value, info.status), (1, CacheStatus::Hit));
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        let (value, info) = cache.peek("key").unwrap();
+        assert_eq!((value, info.status), (1, CacheStatus::Stale));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.peek("key").is_none());
+    }
+}
@@ -0,0 +1,681 @@
+use chrono::{DateTime, Utc};
+use clap::{Parser, ValueEnum};
+use hyper::header::{HeaderName, HeaderValue};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::template::TitleTemplate;
+
+/// A set of programme IDs, given either as a comma-separated list or as the
+/// path to a file with one ID per line (`#` starts a comment)
+#[derive(Clone, Debug, Default)]
+pub struct IdList(HashSet<String>);
+
+impl IdList {
+    pub fn contains(&self, id: &str) -> bool {
+        self.0.contains(id)
+    }
+}
+
+impl FromStr for IdList {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ids = if Path::new(s).is_file() {
+            std::fs::read_to_string(s)?
+                .lines()
+                .map(|l| l.split('#').next().unwrap_or_default().trim().to_string())
+                .filter(|l| !l.is_empty())
+                .collect()
+        } else {
+            s.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        Ok(IdList(ids))
+    }
+}
+
+/// A static header added to every response, given as `Name: value`
+#[derive(Clone, Debug)]
+pub struct ResponseHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for ResponseHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected Name: value, got {:?}", s))?;
+        Ok(ResponseHeader {
+            name: HeaderName::from_str(name.trim())
+                .map_err(|_| format!("invalid header name {:?}", name.trim()))?,
+            value: HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("invalid header value {:?}", value.trim()))?,
+        })
+    }
+}
+
+fn parse_api_version(s: &str) -> Result<String, String> {
+    let valid = s
+        .strip_prefix('v')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("expected a version like v2, got {:?}", s))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TlsVersion {
+    #[value(name = "1.0")]
+    V1_0,
+    #[value(name = "1.1")]
+    V1_1,
+    #[value(name = "1.2")]
+    V1_2,
+}
+
+impl TlsVersion {
+    pub fn to_reqwest(self) -> reqwest::tls::Version {
+        match self {
+            TlsVersion::V1_0 => reqwest::tls::Version::TLS_1_0,
+            TlsVersion::V1_1 => reqwest::tls::Version::TLS_1_1,
+            TlsVersion::V1_2 => reqwest::tls::Version::TLS_1_2,
+        }
+    }
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GuidSource {
+    /// The episode's BBC PID
+    Pid,
+    /// A hash of the programme ID, release date and title
+    Hash,
+    /// The episode's audio file URL
+    Url,
+}
+
+/// How enclosure URLs through the `/audio` endpoint are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EnclosureUrlMode {
+    /// With this proxy's scheme and host, from `--base-url` or the request
+    Absolute,
+    /// As a path, for clients to resolve against the feed's URL
+    Relative,
+}
+
+/// How feed ETags are computed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EtagMode {
+    /// A strong ETag over the exact bytes
+    Strong,
+    /// A weak ETag over everything but volatile fields
+    Weak,
+}
+
+/// Image sizes BBC's image service is known to serve, substituted for the
+/// `{recipe}` placeholder in image URLs. The default for `--image-recipe`.
+const IMAGE_RECIPES: [&str; 16] = [
+    "96x96",
+    "128x128",
+    "160x160",
+    "192x192",
+    "240x240",
+    "288x288",
+    "320x320",
+    "400x400",
+    "480x480",
+    "512x512",
+    "640x640",
+    "800x800",
+    "1024x1024",
+    "1200x1200",
+    "1400x1400",
+    "1920x1920",
+];
+
+/// Command-line configuration for the proxy
+#[derive(Debug, Parser)]
+#[command(version, about = "An RSS proxy for BBC Sounds")]
+pub struct Config {
+    /// Public base URL of this proxy, e.g. `https://sounds.halsted.space`.
+    /// Falls back to the request's Host header when unset.
+    #[arg(long)]
+    pub base_url: Option<String>,
+
+    /// Another hostname this proxy is reachable at, as `host` or
+    /// `host:port`. Requests whose Host header names one get links on that
+    /// host, keeping `--base-url`'s scheme and path; any other Host gets
+    /// `--base-url`. May be given more than once.
+    #[arg(long = "allowed-host")]
+    pub allowed_hosts: Vec<String>,
+
+    /// Point feed enclosures at this proxy's `/audio` endpoint instead of at BBC
+    #[arg(long)]
+    pub proxy_audio: bool,
+
+    /// Answer `/audio` requests with an `X-Accel-Redirect` header so that nginx
+    /// streams the file instead of this process
+    #[arg(long)]
+    pub accel_redirect: bool,
+
+    /// Internal nginx location the upstream audio URL is appended to when
+    /// `--accel-redirect` is set
+    #[arg(long, default_value = "/internal-audio")]
+    pub accel_redirect_location: String,
+
+    /// Format of item titles. Available placeholders are `{primary}`,
+    /// `{secondary}`, `{date}` (YYYY-MM-DD), `{series}` (the programme title)
+    /// and `{episode}` (position in release order, oldest first)
+    #[arg(long, default_value = "{secondary}")]
+    pub title_template: TitleTemplate,
+
+    /// Omit `content:encoded` from items, leaving only the plain `description`
+    #[arg(long)]
+    pub no_content_encoded: bool,
+
+    /// Seconds to cache episode lists and rendered feeds for. With 0, nothing
+    /// is cached but concurrent requests for the same feed still share one
+    /// upstream fetch.
+    #[arg(long, default_value_t = 300)]
+    pub cache_ttl: u64,
+
+    /// Seconds to cache programme metadata for, which changes far less often
+    /// than the episode list
+    #[arg(long, default_value_t = 6 * 60 * 60)]
+    pub container_cache_ttl: u64,
+
+    /// Channel `docs` URL
+    #[arg(long, default_value = "https://www.rssboard.org/rss-specification")]
+    pub docs: String,
+
+    /// Email for the channel's `managingEditor` and `itunes:owner`
+    #[arg(long)]
+    pub managing_editor: Option<String>,
+
+    /// Email for the channel's `webMaster`
+    #[arg(long)]
+    pub webmaster: Option<String>,
+
+    /// Seconds to wait for in-flight requests to finish on shutdown before
+    /// exiting anyway
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_timeout: u64,
+
+    /// Only serve feeds for these programme IDs
+    #[arg(long)]
+    pub allow_ids: Option<IdList>,
+
+    /// Refuse to serve feeds for these programme IDs. Takes precedence over
+    /// `--allow-ids`.
+    #[arg(long)]
+    pub deny_ids: Option<IdList>,
+
+    /// Emit `<podcast:person>` elements for presenters listed by BBC
+    #[arg(long)]
+    pub podcast_person: bool,
+
+    /// Leave out episodes whose titles match a `--trailer-pattern`
+    #[arg(long)]
+    pub drop_trailers: bool,
+
+    /// Words or phrases marking an episode as a trailer, matched
+    /// case-insensitively against whole words of the title
+    #[arg(long = "trailer-pattern", default_values = ["Trailer", "Coming Soon"])]
+    pub trailer_patterns: Vec<String>,
+
+    /// Set each item's `itunes:episodeType` to `trailer` if its title matches
+    /// a `--trailer-pattern`, `bonus` if it matches a `--bonus-pattern`, and
+    /// `full` otherwise
+    #[arg(long)]
+    pub episode_type: bool,
+
+    /// Words or phrases marking an episode as a bonus for `--episode-type`,
+    /// matched like `--trailer-pattern`
+    #[arg(long = "bonus-pattern", default_values = ["Bonus", "Extra"])]
+    pub bonus_patterns: Vec<String>,
+
+    /// Base URL of the BBC API
+    #[arg(long, default_value = "https://rms.api.bbc.co.uk")]
+    pub api_base_url: String,
+
+    /// On startup, fetch `--startup-check-id` from BBC and log whether it worked
+    #[arg(long)]
+    pub startup_check: bool,
+
+    /// Programme ID used by `--startup-check`
+    #[arg(long, default_value = "p02pc9pj")]
+    pub startup_check_id: String,
+
+    /// Exit instead of starting when `--startup-check` fails
+    #[arg(long, requires = "startup_check")]
+    pub fail_fast: bool,
+
+    /// Base of each item's `link`, which the episode PID is appended to
+    #[arg(long, default_value = "https://www.bbc.co.uk/sounds/play")]
+    pub item_link_base: String,
+
+    /// Seconds past `--cache-ttl` that an expired feed may still be served
+    /// when fetching a fresh one from BBC fails
+    #[arg(long, default_value_t = 0)]
+    pub serve_stale: u64,
+
+    /// Add `X-Cache` and `X-Cache-Age` headers to feed responses
+    #[arg(long)]
+    pub debug_headers: bool,
+
+    /// BBC API version path segment, e.g. `v2`
+    #[arg(long, default_value = "v2", value_parser = parse_api_version)]
+    pub api_version: String,
+
+    /// Description for episodes BBC gives no synopsis for. Defaults to the
+    /// episode title.
+    #[arg(long)]
+    pub empty_synopsis_placeholder: Option<String>,
+
+    /// Maximum length of the queue of connections waiting to be accepted
+    #[arg(long, default_value_t = 1024)]
+    pub listen_backlog: i32,
+
+    /// Set `TCP_NODELAY` on accepted connections, disabling Nagle's algorithm
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub tcp_nodelay: bool,
+
+    /// BBC image recipe for the channel's artwork. Apple Podcasts wants at
+    /// least 1400x1400.
+    #[arg(long, default_value = "288x288")]
+    pub channel_image_size: String,
+
+    /// BBC image recipe for each item's artwork
+    #[arg(long, default_value = "288x288")]
+    pub item_image_size: String,
+
+    /// Image recipes `--channel-image-size` and `--item-image-size` may
+    /// use, comma-separated, for when BBC adds or drops sizes. Defaults to
+    /// the sizes known to work.
+    #[arg(long = "image-recipe", value_delimiter = ',', default_values = IMAGE_RECIPES)]
+    pub image_recipes: Vec<String>,
+
+    /// What item GUIDs are derived from. `pid` falls back to `hash` for
+    /// episodes without a PID.
+    #[arg(long, value_enum, default_value_t = GuidSource::Pid)]
+    pub guid_source: GuidSource,
+
+    /// Emit a `<podcast:funding>` link to this URL. Requires `--funding-text`.
+    #[arg(long, requires = "funding_text")]
+    pub funding_url: Option<String>,
+
+    /// Label for the `--funding-url` link, such as "Support the BBC"
+    #[arg(long, requires = "funding_url")]
+    pub funding_text: Option<String>,
+
+    /// Channel `<copyright>` line. Feeds can override it with `?copyright=`.
+    #[arg(long, default_value = "© BBC")]
+    pub copyright: String,
+
+    /// When a later page of a programme's episodes fails to load, serve the
+    /// episodes fetched so far with an `X-Partial: true` header instead of an
+    /// error
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub partial_on_pagination_error: bool,
+
+    /// Convert item titles to title case, for programmes with titles in all
+    /// caps or inconsistent casing
+    #[arg(long)]
+    pub title_case: bool,
+
+    /// Acronyms `--title-case` keeps in this spelling
+    #[arg(long = "acronym", default_values = ["BBC", "UK", "US", "EU", "UN", "NHS", "TV"])]
+    pub acronyms: Vec<String>,
+
+    /// Oldest TLS version to accept from upstream servers. The native TLS
+    /// backend can't be limited to 1.3 alone, so that's not offered.
+    #[arg(long, value_enum, default_value_t = TlsVersion::V1_2)]
+    pub min_tls_version: TlsVersion,
+
+    /// Serve an empty feed rather than a 404 for programmes that exist but
+    /// have no playable episodes yet
+    #[arg(long)]
+    pub empty_on_no_episodes: bool,
+
+    /// Serve `/metrics` and `/health` on this address, such as
+    /// `127.0.0.1:9223`, instead of the public port
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Keep cached feeds gzip-compressed in memory. Clients that accept gzip
+    /// get the compressed bytes as they are.
+    #[arg(long)]
+    pub compress_cache: bool,
+
+    /// Emit `itunes:episode` numbers counted from the oldest episode. These
+    /// are the proxy's own numbering, not BBC's.
+    #[arg(long)]
+    pub synthesize_episode_numbers: bool,
+
+    /// Accept HTTP/2 over cleartext (h2c with prior knowledge) as well as
+    /// HTTP/1.x, for HTTP/2-aware reverse proxies. `--http2 false` serves
+    /// only HTTP/1.x.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub http2: bool,
+
+    /// Add the combined length of the feed's episodes to the channel
+    /// description
+    #[arg(long)]
+    pub show_total_duration: bool,
+
+    /// Save every freshly rendered feed in this directory, and serve the
+    /// saved copy when BBC can't be reached and nothing is cached
+    #[arg(long)]
+    pub fallback_feed_dir: Option<PathBuf>,
+
+    /// Most megabytes of feeds to keep in `--fallback-feed-dir`, removing
+    /// the least recently written beyond that
+    #[arg(long, default_value_t = 100)]
+    pub fallback_feed_dir_size: u64,
+
+    /// Emit a `<dc:creator>` on each item: the episode's presenters, or BBC
+    #[arg(long)]
+    pub dc_creator: bool,
+
+    /// Stream RSS feeds out a page of episodes at a time instead of fetching
+    /// every page first, bounding memory for very large archives. Streamed
+    /// feeds aren't cached.
+    #[arg(long)]
+    pub stream_feeds: bool,
+
+    /// Estimate each enclosure's length from the episode's duration and the
+    /// variant's bitrate instead of trusting BBC's file size, which is wrong
+    /// for some programmes
+    #[arg(long)]
+    pub estimate_length_from_duration: bool,
+
+    /// Most requests one client IP may have in flight at once, beyond which
+    /// it gets 429 Too Many Requests. 0, the default, for no limit. Behind a
+    /// reverse proxy, set `--trusted-proxy-count` too, or every client shares
+    /// the proxy's IP.
+    #[arg(long, default_value_t = 0)]
+    pub max_concurrent_per_ip: usize,
+
+    /// Keep proxied audio in this directory, so that later requests,
+    /// including range requests, are served locally where it has the bytes
+    /// and only the missing ranges are fetched from BBC
+    #[arg(long)]
+    pub audio_cache_dir: Option<PathBuf>,
+
+    /// Most megabytes of audio to keep in `--audio-cache-dir`, evicting the
+    /// least recently served files beyond that
+    #[arg(long, default_value_t = 1024)]
+    pub audio_cache_size: u64,
+
+    /// Header to add to every response, as `Name: value`, replacing any the
+    /// response already has, e.g. `X-Content-Type-Options: nosniff`. May be
+    /// given more than once.
+    #[arg(long = "response-header")]
+    pub response_headers: Vec<ResponseHeader>,
+
+    /// Minutes clients may cache a feed for, advertised as the channel's
+    /// `<ttl>`. Shortened when episodes are about to stop being available, so
+    /// that clients poll again before they expire.
+    #[arg(long, default_value_t = 60)]
+    pub feed_ttl: u64,
+
+    /// JSON file of default feed parameters for particular programmes, such
+    /// as `{"p02nq0gn": {"order": "date_asc", "limit": 20}}`. Parameters in
+    /// the request still take precedence.
+    #[arg(long)]
+    pub overrides: Option<PathBuf>,
+
+    /// Whether `--proxy-audio` enclosure URLs include this proxy's scheme and
+    /// host. Relative URLs suit feeds served at a known base, but not every
+    /// client resolves them.
+    #[arg(long, value_enum, default_value_t = EnclosureUrlMode::Absolute)]
+    pub enclosure_url_mode: EnclosureUrlMode,
+
+    /// When an item field's chosen synopsis is empty, use the nearest length
+    /// BBC did fill in. Accepts on/off as well as true/false.
+    #[arg(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    pub synopsis_fallback: bool,
+
+    /// Reverse proxies in front of this one that append to `X-Forwarded-For`,
+    /// used to find the client's IP for `--max-concurrent-per-ip`. With the
+    /// default of 0 the header is ignored, as clients can forge it.
+    #[arg(long, default_value_t = 0)]
+    pub trusted_proxy_count: usize,
+
+    /// Pin the time feeds are rendered at, as RFC 3339 such as
+    /// `2024-01-01T00:00:00Z`, for reproducible output. The time is used for
+    /// the `?min_days_left=` cutoff, in feeds and timelines, and for the
+    /// channel `<ttl>`, which is shortened when episodes are about to expire.
+    /// Nothing else in a feed body depends on the time. Response headers
+    /// such as `X-Cache-Age` still use the real clock.
+    #[arg(long)]
+    pub deterministic_time: Option<DateTime<Utc>>,
+
+    /// Write item `pubDate`s in UTC rather than with the offset BBC gave,
+    /// for clients that sort mixed offsets wrongly
+    #[arg(long)]
+    pub normalize_dates_utc: bool,
+
+    /// Largest RSS feed to send, in bytes. Bigger feeds lose their oldest
+    /// episodes until they fit, and say so in the channel description.
+    #[arg(long)]
+    pub max_response_bytes: Option<usize>,
+
+    /// Emit a channel `<bbc:containerPid>` with the programme ID the feed was
+    /// built from
+    #[arg(long)]
+    pub container_pid: bool,
+
+    /// Fetch a BBC response once more when it can't be decoded, as it's
+    /// sometimes just cut short
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub retry_on_decode_error: bool,
+
+    /// How feed ETags are computed. `weak` ignores the channel `<ttl>`, which
+    /// can change with the time alone.
+    #[arg(long, value_enum, default_value_t = EtagMode::Strong)]
+    pub etag_mode: EtagMode,
+
+    /// Most redirects to follow for a BBC API request. Proxied audio follows
+    /// up to 10, and only to BBC hosts.
+    #[arg(long, default_value_t = 10)]
+    pub max_redirects: usize,
+
+    /// When BBC redirects a programme ID to another, say so in an
+    /// `X-Canonical-Id` header on the feed, so clients can resubscribe
+    #[arg(long)]
+    pub canonical_id_header: bool,
+
+    /// Start in maintenance mode: requests that would reach BBC get a 503
+    /// with `--maintenance-message`, while `/health` and `/metrics` keep
+    /// answering. SIGHUP switches maintenance mode on and off.
+    #[arg(long)]
+    pub maintenance: bool,
+
+    /// Body of maintenance mode's 503 responses
+    #[arg(
+        long,
+        default_value = "This feed is briefly unavailable for maintenance. Please try again later."
+    )]
+    pub maintenance_message: String,
+
+    /// In maintenance mode, still serve feeds that are cached, including
+    /// stale ones kept by `--serve-stale`
+    #[arg(long)]
+    pub maintenance_serve_cached: bool,
+}
+
+impl Config {
+    /// The versioned BBC API URL, e.g. `https://rms.api.bbc.co.uk/v2`
+    pub fn api_url(&self) -> String {
+        format!(
+            "{}/{}",
+            self.api_base_url.trim_end_matches('/'),
+            self.api_version
+        )
+    }
+
+    /// Check that the image sizes are among the allowed `--image-recipe`s
+    pub fn check_image_recipes(&self) -> Result<(), String> {
+        for (flag, recipe) in [
+            ("--channel-image-size", &self.channel_image_size),
+            ("--item-image-size", &self.item_image_size),
+        ] {
+            if !self.image_recipes.contains(recipe) {
+                return Err(format!(
+                    "{} {} is not one of {}",
+                    flag,
+                    recipe,
+                    self.image_recipes.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The time to render feeds at: `--deterministic-time`, or now
+    pub fn now(&self) -> DateTime<Utc> {
+        self.deterministic_time.unwrap_or_else(Utc::now)
+    }
+
+    pub fn is_id_allowed(&self, id: &str) -> bool {
+        let denied = self.deny_ids.as_ref().is_some_and(|ids| ids.contains(id));
+        let allowed = self.allow_ids.as_ref().is_none_or(|ids| ids.contains(id));
+        allowed && !denied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, get, MockBbc, Upstream, PROGRAMME};
+    use hyper::StatusCode;
+
+    fn config(args: &[&str]) -> Result<Config, clap::Error> {
+        let mut argv = vec!["soundsproxy"];
+        argv.extend_from_slice(args);
+        Config::try_parse_from(argv)
+    }
+
+    #[test]
+    fn response_headers_are_checked_at_startup() {
+        let headers = config(&["--response-header", "X-Frame-Options:  DENY "])
+            .unwrap()
+            .response_headers;
+        assert_eq!(headers[0].name, "x-frame-options");
+        assert_eq!(headers[0].value, "DENY");
+        for bad in ["X-Frame-Options DENY", "Bad Name: x", ": x", "X-Evil: a\nb"] {
+            assert!(config(&["--response-header", bad]).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn api_version_is_part_of_the_api_url() {
+        assert_eq!(
+            config(&[]).unwrap().api_url(),
+            "https://rms.api.bbc.co.uk/v2"
+        );
+        let v3 = config(&[
+            "--api-base-url",
+            "http://localhost:1/",
+            "--api-version",
+            "v3",
+        ]);
+        assert_eq!(v3.unwrap().api_url(), "http://localhost:1/v3");
+        for bad in ["3", "v", "v3/../x", "w3"] {
+            assert!(config(&["--api-version", bad]).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_use_the_configured_api_version() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--api-version", "v3"]);
+        get(&state, &format!("/{}", PROGRAMME)).await;
+        assert_eq!(bbc.hits("/v2/"), 0);
+        assert_eq!(bbc.hits("/v3/programmes/"), 2);
+    }
+
+    #[test]
+    fn denied_ids_win_over_allowed_ones() {
+        let allowed = |args: &[&str], id: &str| config(args).unwrap().is_id_allowed(id);
+        assert!(allowed(&[], "p0000001"));
+        let deny = ["--deny-ids", "p0000002, p0000003"];
+        assert!(allowed(&deny, "p0000001"));
+        assert!(!allowed(&deny, "p0000002") && !allowed(&deny, "p0000003"));
+        let allow = ["--allow-ids", "p0000001,p0000002"];
+        assert!(allowed(&allow, "p0000001") && !allowed(&allow, "p0000004"));
+        let both = ["--allow-ids", "p0000001,p0000002", "--deny-ids", "p0000002"];
+        assert!(allowed(&both, "p0000001") && !allowed(&both, "p0000002"));
+
+        let dir = testing::tempdir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("deny.txt");
+        std::fs::write(&file, "# abusive\np0000002  # spam\n\n p0000003\n").unwrap();
+        let deny = ["--deny-ids", file.to_str().unwrap()];
+        assert!(!allowed(&deny, "p0000002") && !allowed(&deny, "p0000003"));
+        assert!(allowed(&deny, "p0000001"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn denied_ids_are_forbidden_without_asking_bbc() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--deny-ids", PROGRAMME]);
+        for path in [
+            "/p0000001",
+            "/p0000001.csv",
+            "/info/p0000001.json",
+            "/timeline?ids=p0000001&strict=true",
+        ] {
+            assert_eq!(
+                get(&state, path).await.status(),
+                StatusCode::FORBIDDEN,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(bbc.hits(""), 0);
+        let state = testing::state(&bbc, &["--deny-ids", "p0000002"]);
+        assert_eq!(get(&state, "/p0000001").await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn min_tls_version_defaults_to_1_2_and_rejects_others() {
+        let version = |args: &[&str]| config(args).map(|c| c.min_tls_version);
+        assert_eq!(version(&[]).unwrap(), TlsVersion::V1_2);
+        assert_eq!(TlsVersion::V1_2.to_string(), "1.2");
+        for (arg, expected) in [("1.0", TlsVersion::V1_0), ("1.1", TlsVersion::V1_1)] {
+            assert_eq!(version(&["--min-tls-version", arg]).unwrap(), expected);
+            // Every version can be given to the clients
+            let config = config(&["--min-tls-version", arg]).unwrap();
+            assert!(crate::http_clients(&config).is_ok(), "{}", arg);
+        }
+        // 1.3 would only fail later, when the clients are built
+        for bad in ["1.3", "1.4", "tls1.2", "1", ""] {
+            assert!(version(&["--min-tls-version", bad]).is_err(), "{:?}", bad);
+        }
+    }
+}
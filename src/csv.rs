@@ -0,0 +1,140 @@
+use crate::audio;
+use crate::bbc::PodEpisodes;
+use crate::options::FeedOptions;
+use crate::{release_date, select_variant};
+
+/// Quote a field if it contains a comma, quote or line break, as RFC 4180
+/// describes
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn row(out: &mut String, fields: &[&str]) {
+    let fields: Vec<String> = fields.iter().map(|f| field(f)).collect();
+    out.push_str(&fields.join(","));
+    out.push_str("\r\n");
+}
+
+/// Render episodes as CSV, one row per episode, with the enclosure details
+/// the RSS feed would have chosen
+pub fn build_csv(episodes: &PodEpisodes, opts: &FeedOptions, audio_base: Option<&str>) -> String {
+    let mut out = String::new();
+    row(
+        &mut out,
+        &[
+            "title",
+            "release_date",
+            "duration_seconds",
+            "file_size",
+            "bitrate",
+            "file_url",
+        ],
+    );
+    for e in &episodes.data {
+        let variant = select_variant(&e.download.quality_variants, opts);
+        let file_url = match audio_base {
+            Some(base) => audio::proxy_url(base, &variant.file_url),
+            None => variant.file_url.clone(),
+        };
+        row(
+            &mut out,
+            &[
+                &e.titles.secondary,
+                &release_date(e).to_rfc3339(),
+                &e.duration.value.to_string(),
+                &variant.file_size.to_string(),
+                &variant.bitrate.to_string(),
+                &file_url,
+            ],
+        );
+    }
+    out
+}
+
+/// A `Content-Disposition` filename for a programme's CSV, keeping only
+/// characters that are safe in any filesystem and header
+pub fn filename(title: &str, id: &str) -> String {
+    let name: String = title
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if name.is_empty() {
+        format!("{}.csv", id)
+    } else {
+        format!("{}.csv", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, get, text, MockBbc, Upstream, PROGRAMME};
+
+    #[test]
+    fn fields_are_quoted_only_when_they_need_to_be() {
+        assert_eq!(field("Plain title"), "Plain title");
+        assert_eq!(field("Bread, Butter"), "\"Bread, Butter\"");
+        assert_eq!(field("The \"Best\" Bits"), "\"The \"\"Best\"\" Bits\"");
+        assert_eq!(field("One\nTwo"), "\"One\nTwo\"");
+        assert_eq!(field("One\rTwo"), "\"One\rTwo\"");
+        assert_eq!(field(""), "");
+    }
+
+    #[test]
+    fn one_row_per_episode_with_the_chosen_variant() {
+        let mut e = testing::episode(2);
+        e.titles.secondary = "Rock, \"Paper\"".to_string();
+        let episodes = PodEpisodes {
+            data: vec![e, testing::episode(1)],
+            total: 2,
+            partial: false,
+        };
+        let opts = FeedOptions::from_query(Some("max_size=2.5")).unwrap();
+        assert_eq!(
+            build_csv(&episodes, &opts, None),
+            "title,release_date,duration_seconds,file_size,bitrate,file_url\r\n\
+             \"Rock, \"\"Paper\"\"\",2024-01-02T10:00:00+00:00,120,2000000,96,\
+             https://open.live.bbc.co.uk/Medium/2.mp3\r\n\
+             Episode 1,2024-01-01T10:00:00+00:00,60,2000000,96,\
+             https://open.live.bbc.co.uk/Medium/1.mp3\r\n"
+        );
+    }
+
+    #[test]
+    fn filenames_keep_only_safe_characters() {
+        assert_eq!(filename("In Our Time", "p0000001"), "In-Our-Time.csv");
+        assert_eq!(
+            filename("Today: 6/7 \"Live\"", "p0000001"),
+            "Today-67-Live.csv"
+        );
+        assert_eq!(filename("¿¡!", "p0000001"), "p0000001.csv");
+    }
+
+    #[tokio::test]
+    async fn csv_route_is_an_attachment() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let response = get(&state, &format!("/{}.csv", PROGRAMME)).await;
+        assert_eq!(
+            testing::header(&response, "content-type"),
+            "text/csv; charset=utf-8"
+        );
+        assert_eq!(
+            testing::header(&response, "content-disposition"),
+            "attachment; filename=\"Programme.csv\""
+        );
+        let csv = text(response).await;
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.lines().nth(1).unwrap().starts_with("Episode 1,"));
+    }
+}
@@ -0,0 +1,205 @@
+use hyper::{header, Body, Response, StatusCode};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::markup;
+
+/// Reasons a feed couldn't be produced. Cloneable so that one failed upstream
+/// fetch can be handed to every request waiting on it.
+#[derive(Clone, Debug)]
+pub enum ProxyError {
+    /// The request itself was invalid
+    BadRequest(String),
+    /// The programme is blocked by configuration
+    Forbidden(String),
+    /// BBC reported that the programme doesn't exist
+    NotFound(String),
+    /// The request to BBC failed or its response couldn't be read
+    Upstream(Arc<reqwest::Error>),
+    /// BBC's response body wasn't the JSON we expected
+    Decode(Arc<serde_json::Error>),
+    /// BBC answered with an empty body, which is usually transient
+    EmptyResponse,
+    /// BBC answered with something other than JSON, such as an HTML error
+    /// page, of the given content type
+    UnexpectedContentType(String),
+    /// The client already has as many requests in flight as it's allowed
+    TooManyRequests,
+    /// The task rendering the feed panicked
+    FetchPanicked,
+}
+
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ProxyError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ProxyError::NotFound(_) => StatusCode::NOT_FOUND,
+            ProxyError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::Upstream(_)
+            | ProxyError::Decode(_)
+            | ProxyError::EmptyResponse
+            | ProxyError::UnexpectedContentType(_)
+            | ProxyError::FetchPanicked => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// How much the error tells a client about what went wrong: a definite
+    /// answer from BBC or about the request, then a bad BBC response, then a
+    /// timeout, then any other network failure
+    fn informativeness(&self) -> u8 {
+        match self {
+            ProxyError::BadRequest(_)
+            | ProxyError::Forbidden(_)
+            | ProxyError::NotFound(_)
+            | ProxyError::TooManyRequests => 3,
+            ProxyError::Decode(_)
+            | ProxyError::EmptyResponse
+            | ProxyError::UnexpectedContentType(_)
+            | ProxyError::FetchPanicked => 2,
+            ProxyError::Upstream(e) if e.is_status() => 2,
+            ProxyError::Upstream(e) if e.is_timeout() => 1,
+            ProxyError::Upstream(_) => 0,
+        }
+    }
+
+    /// Of two errors from related fetches, the one to report, preferring `a`
+    /// when they're equally informative
+    pub fn most_informative<'a>(a: &'a Self, b: &'a Self) -> &'a Self {
+        if b.informativeness() > a.informativeness() {
+            b
+        } else {
+            a
+        }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        Response::builder()
+            .status(self.status())
+            .body(Body::from(self.to_string()))
+            .unwrap()
+    }
+
+    /// A page for people who open the feed in a browser, explaining that the
+    /// problem is probably temporary, with a link to `retry_url`
+    pub fn into_html_response(self, retry_url: &str) -> Response<Body> {
+        let html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Feed unavailable</title></head>\n\
+             <body><h1>Feed unavailable</h1>\n\
+             <p>BBC Sounds didn't answer properly, which is usually temporary. \
+             Please try again in a few minutes.</p>\n\
+             <p><a href=\"{}\">Try again</a></p>\n\
+             <p><small>{}</small></p></body></html>\n",
+            markup::escape(retry_url),
+            markup::escape(&self.to_string())
+        );
+        Response::builder()
+            .status(self.status())
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(html))
+            .unwrap()
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::BadRequest(message)
+            | ProxyError::Forbidden(message)
+            | ProxyError::NotFound(message) => f.write_str(message),
+            ProxyError::Upstream(e) => e.fmt(f),
+            ProxyError::Decode(e) => write!(f, "Invalid response from BBC: {}", e),
+            ProxyError::EmptyResponse => f.write_str("BBC returned an empty response"),
+            ProxyError::UnexpectedContentType(content_type) => {
+                write!(f, "BBC returned {} instead of JSON", content_type)
+            }
+            ProxyError::TooManyRequests => f.write_str("Too many concurrent requests"),
+            ProxyError::FetchPanicked => f.write_str("Failed to render the feed"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from(e: reqwest::Error) -> Self {
+        ProxyError::Upstream(Arc::new(e))
+    }
+}
+
+impl From<serde_json::Error> for ProxyError {
+    fn from(e: serde_json::Error) -> Self {
+        ProxyError::Decode(Arc::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, get, MockBbc, Raw, Upstream};
+    use std::time::Duration;
+
+    /// The name of whichever of `a` and `b` is reported
+    fn reported(a: &(&str, ProxyError), b: &(&str, ProxyError)) -> String {
+        let chosen = ProxyError::most_informative(&a.1, &b.1);
+        let name = if std::ptr::eq(chosen, &a.1) { a.0 } else { b.0 };
+        name.to_string()
+    }
+
+    #[tokio::test]
+    async fn the_most_informative_error_is_reported() {
+        let client = reqwest::Client::new();
+        // Nothing listens on port 1
+        let refused = client.get("http://127.0.0.1:1/").send().await.unwrap_err();
+        // A listener that never answers
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let timed_out = client
+            .get(format!("http://{}/", silent.local_addr().unwrap()))
+            .timeout(Duration::from_millis(50))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(timed_out.is_timeout());
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let status = client
+            .get(format!("{}/nothing", bbc.url))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err();
+        let decode = serde_json::from_str::<u8>("x").unwrap_err();
+
+        // From most to least informative
+        let errors = [
+            ("not found", ProxyError::NotFound("p0000001".to_string())),
+            ("status", status.into()),
+            ("timeout", timed_out.into()),
+            ("refused", refused.into()),
+        ];
+        for (i, a) in errors.iter().enumerate() {
+            for b in &errors[i + 1..] {
+                assert_eq!(reported(a, b), a.0, "{} over {}", a.0, b.0);
+                assert_eq!(reported(b, a), a.0, "{} over {}", a.0, b.0);
+            }
+        }
+        // Equals go to the first
+        let decode = ("decode", decode.into());
+        assert_eq!(reported(&decode, &errors[1]), "decode");
+        assert_eq!(reported(&errors[1], &decode), "status");
+    }
+
+    #[tokio::test]
+    async fn a_missing_programme_is_reported_over_a_failed_fetch() {
+        // The container fails, and BBC doesn't know the episodes
+        let mut upstream = Upstream::default();
+        upstream.raw.insert(
+            "/v2/programmes/p0000404/container".to_string(),
+            Raw::new(503, "text/plain", "Service Unavailable"),
+        );
+        let bbc = MockBbc::start(upstream).await;
+        let state = testing::state(&bbc, &[]);
+        let response = get(&state, "/p0000404").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
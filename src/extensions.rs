@@ -0,0 +1,60 @@
+use rss::extension::{Extension, ExtensionMap};
+use rss::Channel;
+
+/// Namespaces for the custom elements we emit, by prefix
+const NAMESPACES: [(&str, &str); 3] = [
+    ("podcast", "https://podcastindex.org/namespace/1.0"),
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    // Our own, for BBC identifiers; not defined by BBC
+    ("bbc", "https://github.com/wilt00/soundsproxy#bbc"),
+];
+
+/// A `<name attr="...">value</name>` element, where `name` includes the
+/// namespace prefix
+pub fn element(name: &str, value: Option<String>, attrs: &[(&str, &str)]) -> Extension {
+    Extension {
+        name: name.to_string(),
+        value,
+        attrs: attrs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        children: Default::default(),
+    }
+}
+
+/// Add `ext` to `map` under its namespace prefix
+pub fn insert(map: &mut ExtensionMap, ext: Extension) {
+    let (prefix, _) = ext.name.split_once(':').unwrap_or(("", ""));
+    map.entry(prefix.to_string())
+        .or_default()
+        .entry(ext.name.clone())
+        .or_default()
+        .push(ext);
+}
+
+/// Declare the namespaces of any custom elements in the channel or its items.
+/// The rss crate only does this for the extensions it knows about.
+pub fn declare_namespaces(channel: &mut Channel) {
+    for (prefix, url) in NAMESPACES {
+        let used = channel.extensions.contains_key(prefix)
+            || channel
+                .items
+                .iter()
+                .any(|item| item.extensions.contains_key(prefix));
+        if used {
+            channel
+                .namespaces
+                .insert(prefix.to_string(), url.to_string());
+        }
+    }
+}
+
+/// Declare every namespace, for channels written before their items are known
+pub fn declare_all_namespaces(channel: &mut Channel) {
+    for (prefix, url) in NAMESPACES {
+        channel
+            .namespaces
+            .insert(prefix.to_string(), url.to_string());
+    }
+}
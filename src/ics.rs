@@ -0,0 +1,122 @@
+use chrono::Utc;
+
+use crate::bbc::{PodContainer, PodEpisodes};
+
+/// Escape a TEXT value as described in RFC 5545 section 3.3.11. Any line
+/// break becomes `\n`, since a bare CR would end the content line.
+fn escape(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Write a content line, folding it at 75 octets as RFC 5545 requires
+fn line(out: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// Render episodes as an iCalendar with one VEVENT per episode, spanning its
+/// release time and duration. Episodes without a valid release date are
+/// skipped.
+pub fn build_ics(id: &str, info: &PodContainer, episodes: &PodEpisodes) -> String {
+    let mut out = String::new();
+    line(&mut out, "BEGIN:VCALENDAR");
+    line(&mut out, "VERSION:2.0");
+    line(&mut out, "PRODID:-//soundsproxy//EN");
+    line(
+        &mut out,
+        &format!("X-WR-CALNAME:{}", escape(&info.titles.primary)),
+    );
+    for e in &episodes.data {
+        let start = match e.release_date() {
+            Some(date) => date
+                .with_timezone(&Utc)
+                .format("%Y%m%dT%H%M%SZ")
+                .to_string(),
+            None => continue,
+        };
+        let uid = if e.id.is_empty() {
+            format!("{}-{}@soundsproxy", id, start)
+        } else {
+            format!("{}@soundsproxy", e.id)
+        };
+        line(&mut out, "BEGIN:VEVENT");
+        line(&mut out, &format!("UID:{}", uid));
+        line(&mut out, &format!("DTSTAMP:{}", start));
+        line(&mut out, &format!("DTSTART:{}", start));
+        line(&mut out, &format!("DURATION:PT{}S", e.duration.value));
+        line(
+            &mut out,
+            &format!("SUMMARY:{}", escape(&e.titles.secondary)),
+        );
+        line(
+            &mut out,
+            &format!("DESCRIPTION:{}", escape(&e.synopses.long)),
+        );
+        line(&mut out, "END:VEVENT");
+    }
+    line(&mut out, "END:VCALENDAR");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(escape("a, b; c\\d"), "a\\, b\\; c\\\\d");
+        assert_eq!(
+            escape("one\ntwo\r\nthree\rfour"),
+            "one\\ntwo\\nthree\\nfour"
+        );
+    }
+
+    #[test]
+    fn long_lines_are_folded_between_characters() {
+        let mut out = String::new();
+        let content = format!("SUMMARY:{}", "é".repeat(50));
+        line(&mut out, &content);
+        let lines: Vec<&str> = out.trim_end_matches("\r\n").split("\r\n").collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| l.len() <= 75));
+        assert!(lines[1..].iter().all(|l| l.starts_with(' ')));
+        let unfolded: String = lines
+            .iter()
+            .enumerate()
+            .map(|(i, l)| if i == 0 { *l } else { &l[1..] })
+            .collect();
+        assert_eq!(unfolded, content);
+    }
+
+    #[test]
+    fn episodes_become_events() {
+        let mut episodes = PodEpisodes {
+            data: vec![testing::episode(1), testing::episode(2)],
+            ..PodEpisodes::default()
+        };
+        episodes.data[0].titles.secondary = "Part 1, the start".to_string();
+        episodes.data[1].release.date = "not a date".to_string();
+        let ics = build_ics(testing::PROGRAMME, &testing::container(), &episodes);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 1);
+        assert!(ics.contains("UID:e0000001@soundsproxy\r\n"));
+        assert!(ics.contains("DTSTART:20240101T100000Z\r\n"));
+        assert!(ics.contains("DURATION:PT60S\r\n"));
+        assert!(ics.contains("SUMMARY:Part 1\\, the start\r\n"));
+    }
+}
@@ -0,0 +1,73 @@
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::bbc::{self, PodSynopses, PodTitles};
+use crate::cache::CachePolicy;
+use crate::{check_id, replace_img_url, AppState};
+
+#[derive(Serialize)]
+struct Info<'a> {
+    id: &'a str,
+    titles: &'a PodTitles,
+    synopses: &'a PodSynopses,
+    image_url: String,
+}
+
+/// `GET /info/{id}.json`: a programme's metadata, without its episodes
+pub async fn get_info(state: &AppState, id: &str) -> Response<Body> {
+    if let Err(e) = check_id(state, id) {
+        return e.into_response();
+    }
+    let container = match bbc::container(state, id, CachePolicy::Normal).await {
+        Ok(container) => container,
+        Err(e) => return e.into_response(),
+    };
+    let info = Info {
+        id,
+        titles: &container.titles,
+        synopses: &container.synopses,
+        image_url: replace_img_url(&container.image_url, &state.config.channel_image_size),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&info).unwrap()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, get, text, MockBbc, Upstream, PROGRAMME};
+    use hyper::StatusCode;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn info_is_the_containers_metadata_as_json() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let path = format!("/info/{}.json", PROGRAMME);
+        let response = get(&state, &path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            testing::header(&response, "content-type"),
+            "application/json"
+        );
+        let info: Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!(
+            info,
+            json!({
+                "id": PROGRAMME,
+                "titles": { "primary": "Programme", "secondary": "" },
+                "synopses": { "short": "Short", "medium": "Medium", "long": "Long" },
+                "image_url": "https://ichef.bbci.co.uk/images/ic/288x288/p.jpg",
+            })
+        );
+        // The container is cached, and the episodes never fetched
+        assert_eq!(get(&state, &path).await.status(), StatusCode::OK);
+        assert_eq!(bbc.hits("/container"), 1);
+        assert_eq!(bbc.hits("/playable"), 0);
+
+        let response = get(&state, "/info/p0000002.json").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
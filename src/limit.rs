@@ -0,0 +1,107 @@
+use hyper::header::HeaderMap;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Requests currently being handled for each client IP
+#[derive(Default)]
+pub struct ConcurrencyLimit {
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Holds one of an IP's request slots until dropped, however the request ends
+pub struct Slot {
+    limit: Arc<ConcurrencyLimit>,
+    ip: IpAddr,
+}
+
+impl ConcurrencyLimit {
+    fn lock(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Take a slot for `ip`, or `None` if it already has `max` requests in
+    /// flight
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr, max: usize) -> Option<Slot> {
+        let mut active = self.lock();
+        let count = active.entry(ip).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            limit: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut active = self.limit.lock();
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The client's IP address. With `trusted` reverse proxies in front of us,
+/// each of which appends the address it received the request from to
+/// `X-Forwarded-For`, the client is the `trusted`th address from the right;
+/// anything further left could have been made up by the client.
+pub fn client_ip(remote: IpAddr, headers: &HeaderMap, trusted: usize) -> IpAddr {
+    if trusted == 0 {
+        return remote;
+    }
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    // Fewer hops than proxies means the request skipped some of them, so take
+    // the earliest we have
+    let hop = hops
+        .len()
+        .checked_sub(trusted)
+        .map_or(hops.first(), |i| hops.get(i));
+    hop.and_then(|ip| ip.parse().ok()).unwrap_or(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(forwarded: &[&str], trusted: usize) -> String {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        let remote = IpAddr::from([10, 0, 0, 1]);
+        client_ip(remote, &headers, trusted).to_string()
+    }
+
+    #[test]
+    fn client_is_the_address_the_first_trusted_proxy_saw() {
+        let forwarded = ["6.6.6.6, 203.0.113.7", "192.168.1.2"];
+        // Without trusted proxies the header is ignored
+        assert_eq!(client(&forwarded, 0), "10.0.0.1");
+        assert_eq!(client(&forwarded, 1), "192.168.1.2");
+        assert_eq!(client(&forwarded, 2), "203.0.113.7");
+        assert_eq!(client(&forwarded, 3), "6.6.6.6");
+        // More trusted proxies than hops takes the earliest
+        assert_eq!(client(&forwarded, 9), "6.6.6.6");
+        assert_eq!(client(&["2001:db8::1"], 1), "2001:db8::1");
+    }
+
+    #[test]
+    fn unusable_forwarded_addresses_fall_back_to_the_peer() {
+        assert_eq!(client(&[], 1), "10.0.0.1");
+        assert_eq!(client(&["unknown"], 1), "10.0.0.1");
+        assert_eq!(client(&["203.0.113.7, "], 1), "10.0.0.1");
+    }
+}
@@ -1,239 +1,1442 @@
-use chrono::{DateTime, FixedOffset, TimeZone};
-use futures::try_join;
+mod audio;
+mod audio_cache;
+mod bbc;
+mod cache;
+mod config;
+mod csv;
+mod error;
+mod extensions;
+mod ics;
+mod info;
+mod limit;
+mod markup;
+mod metrics;
+mod options;
+mod overrides;
+mod range;
+mod resolve;
+mod stream;
+mod template;
+#[cfg(test)]
+mod testing;
+mod timeline;
+mod title_case;
+mod validate;
+
+use audio_cache::AudioCache;
+use bbc::{
+    PodContainer, PodContributor, PodEpisode, PodEpisodes, PodQualityVariant, PodQualityVariants,
+};
+use cache::{Cache, CachePolicy, CacheStatus};
+use chrono::{DateTime, Datelike, FixedOffset, TimeZone, Utc};
+use clap::Parser;
+use config::{Config, EnclosureUrlMode, EtagMode, GuidSource};
+use error::ProxyError;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{join, StreamExt};
 use hhmmss::Hhmmss;
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::uri::Authority;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
-use rss::extension::itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder};
-use rss::{ChannelBuilder, EnclosureBuilder, ItemBuilder};
-use serde::{Deserialize, Serialize};
+use limit::ConcurrencyLimit;
+use metrics::Metrics;
+use options::{DescFormat, FeedOptions, FilterField, Format, Order, SynopsisLength};
+use overrides::Overrides;
+use percent_encoding::percent_decode_str;
+use range::parse_range;
+use rss::extension::itunes::{
+    ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder, ITunesOwnerBuilder,
+};
+use rss::{ChannelBuilder, EnclosureBuilder, GuidBuilder, ItemBuilder};
 use std::collections::BTreeMap;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use template::TitleFields;
+use url::form_urlencoded;
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 type ApiResult<T> = std::result::Result<T, GenericError>;
 
 const PORT: u16 = 8223;
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodContainer {
-    titles: PodTitles,
-    synopses: PodSynopses,
-    image_url: String,
+/// State shared by all request handlers
+pub struct AppState {
+    config: Config,
+    /// For the BBC API
+    client: reqwest::Client,
+    /// For proxied audio, which may only be redirected to BBC hosts
+    audio_client: reqwest::Client,
+    /// Rendered feeds, keyed by base URL and request path
+    feeds: Cache<RenderedFeed>,
+    /// Programme metadata from BBC, keyed by programme ID
+    containers: Cache<Arc<PodContainer>>,
+    /// Episode lists from BBC, keyed by programme ID
+    episodes: Cache<Arc<PodEpisodes>>,
+    metrics: Metrics,
+    /// Requests in flight per client IP, for `--max-concurrent-per-ip`
+    concurrency: Arc<ConcurrencyLimit>,
+    /// Proxied audio kept on disk, with `--audio-cache-dir`
+    audio_cache: Option<Arc<AudioCache>>,
+    overrides: Overrides,
+    /// Whether maintenance mode is on, from `--maintenance` and SIGHUP
+    maintenance: AtomicBool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodSynopses {
-    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
-    short: String,
-    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
-    medium: String,
-    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
-    long: String,
+impl AppState {
+    fn new(
+        config: Config,
+        client: reqwest::Client,
+        audio_client: reqwest::Client,
+        audio_cache: Option<Arc<AudioCache>>,
+        overrides: Overrides,
+    ) -> Self {
+        let maintenance = config.maintenance;
+        AppState {
+            feeds: Cache::new(
+                Duration::from_secs(config.cache_ttl),
+                Duration::from_secs(config.serve_stale),
+            ),
+            containers: Cache::new(
+                Duration::from_secs(config.container_cache_ttl),
+                Duration::ZERO,
+            ),
+            episodes: Cache::new(Duration::from_secs(config.cache_ttl), Duration::ZERO),
+            config,
+            client,
+            audio_client,
+            metrics: Metrics::default(),
+            concurrency: Arc::default(),
+            audio_cache,
+            overrides,
+            maintenance: AtomicBool::new(maintenance),
+        }
+    }
+
+    fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodEpisodes {
-    data: Vec<PodEpisode>,
+/// `value`, unless it's empty. iTunes elements are left out rather than
+/// written empty, which validators reject.
+fn non_empty(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodEpisode {
-    titles: PodTitles,
-    synopses: PodSynopses,
-    image_url: String,
-    duration: PodDuration,
-    download: PodDownload,
-    release: PodRelease,
+fn replace_img_url(input: &str, recipe: &str) -> String {
+    input.replace("{recipe}", recipe)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodTitles {
-    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
-    primary: String,
-    #[serde(deserialize_with = "serde_with::rust::default_on_null::deserialize")]
-    secondary: String,
+fn release_date(e: &PodEpisode) -> DateTime<FixedOffset> {
+    e.release_date()
+        .unwrap_or_else(|| FixedOffset::east(0).timestamp(0, 0))
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodDuration {
-    value: u64,
-    label: String,
+/// Whether `phrase` appears in `title` as whole words, ignoring case, so that
+/// "Trailer" matches "Series 2 Trailer" but not "Trailers for Sale"
+fn contains_phrase(title: &str, phrase: &str) -> bool {
+    let title = title.to_lowercase();
+    let phrase = phrase.to_lowercase();
+    !phrase.is_empty()
+        && title.match_indices(&phrase).any(|(i, m)| {
+            let before = title[..i].chars().next_back();
+            let after = title[i + m.len()..].chars().next();
+            !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+        })
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodDownload {
-    #[serde(rename = "type")]
-    download_type: String, // "non-drm"
-    quality_variants: PodQualityVariants,
+fn is_trailer(e: &PodEpisode, config: &Config) -> bool {
+    config
+        .trailer_patterns
+        .iter()
+        .any(|p| contains_phrase(&e.titles.secondary, p))
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodQualityVariants {
-    low: PodQualityVariant,
-    medium: PodQualityVariant,
-    high: PodQualityVariant,
+/// The `itunes:episodeType` for an episode, from its title
+fn episode_type(e: &PodEpisode, config: &Config) -> &'static str {
+    if is_trailer(e, config) {
+        "trailer"
+    } else if config
+        .bonus_patterns
+        .iter()
+        .any(|p| contains_phrase(&e.titles.secondary, p))
+    {
+        "bonus"
+    } else {
+        "full"
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodQualityVariant {
-    bitrate: u32,
-    file_url: String,
-    file_size: u32,
-    label: String,
+/// 64-bit FNV-1a, used rather than `DefaultHasher` because GUIDs must stay the
+/// same across builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodRelease {
-    date: String,
-    label: String,
+fn guid(id: &str, e: &PodEpisode, source: GuidSource) -> rss::Guid {
+    let value = match source {
+        GuidSource::Pid if !e.id.is_empty() => e.id.clone(),
+        GuidSource::Url => e.download.quality_variants.high.file_url.clone(),
+        GuidSource::Pid | GuidSource::Hash => {
+            let key = format!("{}\n{}\n{}", id, e.release.date, e.titles.secondary);
+            format!("{:016x}", fnv1a(key.as_bytes()))
+        }
+    };
+    GuidBuilder::default().value(value).permalink(false).build()
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodError {
-    id: String,
-    href: String,
-    status: u32,
-    message: String,
-    replied_at: String,
+/// Render a plain-text synopsis as HTML paragraphs for `content:encoded`
+fn synopsis_html(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| format!("<p>{}</p>", markup::escape(l)))
+        .collect()
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct PodErrors {
-    errors: Vec<PodError>,
+/// Number episodes by release order, oldest first, so that numbers stay the
+/// same as new episodes are added
+fn number_episodes(episodes: &mut [PodEpisode]) {
+    let mut order: Vec<usize> = (0..episodes.len()).collect();
+    order.sort_by_key(|&i| release_date(&episodes[i]));
+    for (n, i) in order.into_iter().enumerate() {
+        episodes[i].number = n + 1;
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(untagged)]
-enum PodContainerResponse {
-    Success(PodContainer),
-    Failure(PodErrors),
+/// `<podcast:person role="host">` elements for the presenters among
+/// `contributors`
+fn podcast_hosts(contributors: &[PodContributor]) -> rss::extension::ExtensionMap {
+    let mut map = rss::extension::ExtensionMap::new();
+    for c in contributors.iter().filter(|c| c.is_presenter()) {
+        let person =
+            extensions::element("podcast:person", Some(c.name.clone()), &[("role", "host")]);
+        extensions::insert(&mut map, person);
+    }
+    map
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(untagged)]
-enum PodEpisodesResponse {
-    Success(PodEpisodes),
-    Failure(PodErrors),
+fn item_extensions(e: &PodEpisode, config: &Config) -> rss::extension::ExtensionMap {
+    let mut map = if config.podcast_person {
+        podcast_hosts(&e.contributors)
+    } else {
+        Default::default()
+    };
+    if config.dc_creator {
+        let presenters: Vec<&str> = e
+            .contributors
+            .iter()
+            .filter(|c| c.is_presenter())
+            .map(|c| c.name.as_str())
+            .collect();
+        let creator = if presenters.is_empty() {
+            "BBC".to_string()
+        } else {
+            presenters.join(", ")
+        };
+        extensions::insert(
+            &mut map,
+            extensions::element("dc:creator", Some(creator), &[]),
+        );
+    }
+    map
 }
 
-async fn get_pod_info(id: &str) -> Result<PodContainerResponse, reqwest::Error> {
-    let url = format!("https://rms.api.bbc.co.uk/v2/programmes/{}/container", id);
-    let client = reqwest::Client::builder()
-        .user_agent("soundsproxy/0.1")
-        .build()?;
-    client
-        .get(url)
-        .send()
-        .await?
-        .json::<PodContainerResponse>()
-        .await
+/// The enclosure to use for an episode: the highest quality that fits within
+/// `max_size`, or the smallest if none do
+fn select_variant<'a>(
+    variants: &'a PodQualityVariants,
+    opts: &FeedOptions,
+) -> &'a PodQualityVariant {
+    let by_quality = [&variants.high, &variants.medium, &variants.low];
+    match opts.max_size {
+        None => &variants.high,
+        Some(max) => by_quality
+            .iter()
+            .find(|v| u64::from(v.file_size) <= max)
+            .copied()
+            .unwrap_or_else(|| by_quality.iter().min_by_key(|v| v.file_size).unwrap()),
+    }
 }
 
-async fn get_pod_episodes(id: &str) -> Result<PodEpisodesResponse, reqwest::Error> {
-    let url = format!(
-        "https://rms.api.bbc.co.uk/v2/programmes/playable?container={}&sort=sequential&type=episode&experience=domestic",
-         id);
-    let client = reqwest::Client::builder()
-        .user_agent("soundsproxy/0.1")
-        .build()?;
-    client
-        .get(url)
-        .send()
-        .await?
-        .json::<PodEpisodesResponse>()
-        .await
+/// One episode's `<item>`. `audio_base` is this proxy's base URL when
+/// enclosures should be routed through the `/audio` endpoint.
+fn build_item(
+    id: &str,
+    info: &PodContainer,
+    e: &PodEpisode,
+    config: &Config,
+    opts: &FeedOptions,
+    audio_base: Option<&str>,
+) -> rss::Item {
+    let series_link = series_link(id);
+    let variant = select_variant(&e.download.quality_variants, opts);
+    let encl = (!opts.no_enclosures).then(|| {
+        let file_url = &variant.file_url;
+        let length = if config.estimate_length_from_duration {
+            variant.estimated_size(e.duration.value)
+        } else {
+            u64::from(variant.file_size)
+        };
+        EnclosureBuilder::default()
+            .mime_type(opts.mime.unwrap_or("audio/mpeg").to_string())
+            .length(length.to_string())
+            .url(match audio_base {
+                Some(base) => audio::proxy_url(base, file_url),
+                None => file_url.clone(),
+            })
+            .build()
+    });
+    let placeholder = e.synopses.is_empty().then(|| {
+        log::debug!("episode {} of {} has no synopsis", e.id, id);
+        config
+            .empty_synopsis_placeholder
+            .clone()
+            .unwrap_or_else(|| e.titles.secondary.clone())
+    });
+    let synopsis = |length: SynopsisLength| match &placeholder {
+        Some(placeholder) => placeholder.clone(),
+        None if config.synopsis_fallback => length.of_or_nearest(&e.synopses).to_string(),
+        None => length.of(&e.synopses).to_string(),
+    };
+    let with_label = |text: String| {
+        if opts.show_release_label && !e.release.label.is_empty() {
+            format!("{}\n{}", e.release.label, text)
+        } else {
+            text
+        }
+    };
+    let fields = &opts.synopses;
+    let description = with_label(synopsis(fields.description));
+    let content = with_label(synopsis(fields.content.unwrap_or(fields.description)));
+    let (description, content) = match opts.desc_format {
+        DescFormat::Html => (description, synopsis_html(&content)),
+        DescFormat::Text => (markup::to_text(&description), markup::to_text(&content)),
+        DescFormat::Markdown => (
+            markup::to_markdown(&description),
+            markup::to_markdown(&content),
+        ),
+    };
+    let itunes_ext = ITunesItemExtensionBuilder::default()
+        .image(non_empty(replace_img_url(
+            &e.image_url,
+            opts.image_size.as_ref().unwrap_or(&config.item_image_size),
+        )))
+        .duration(Duration::new(e.duration.value, 0).hhmmss())
+        .subtitle(non_empty(synopsis(fields.subtitle)))
+        .summary(fields.summary.map(synopsis).and_then(non_empty))
+        .episode_type(
+            config
+                .episode_type
+                .then(|| episode_type(e, config).to_string()),
+        )
+        .episode(
+            (config.synthesize_episode_numbers || opts.serial_mode).then(|| e.number.to_string()),
+        )
+        .build();
+    let date = release_date(e);
+    let title = config.title_template.render(&TitleFields {
+        primary: &e.titles.primary,
+        secondary: &e.titles.secondary,
+        date: &date.format("%Y-%m-%d").to_string(),
+        series: &info.titles.primary,
+        episode: e.number,
+    });
+    let title = if config.title_case {
+        title_case::title_case(&title, &config.acronyms)
+    } else {
+        title
+    };
+    // Say which quality the enclosure is, e.g. "Title (High)"
+    let title = if opts.show_quality && !opts.no_enclosures && !variant.label.is_empty() {
+        format!("{} ({})", title, variant.label)
+    } else {
+        title
+    };
+    let link = if e.id.is_empty() {
+        series_link.to_string()
+    } else {
+        format!("{}/{}", config.item_link_base.trim_end_matches('/'), e.id)
+    };
+    ItemBuilder::default()
+        .title(title)
+        .link(link)
+        .guid(guid(id, e, config.guid_source))
+        .content(if config.no_content_encoded {
+            None
+        } else {
+            Some(content)
+        })
+        .description(description)
+        .itunes_ext(itunes_ext)
+        .enclosure(encl)
+        .pub_date(if config.normalize_dates_utc {
+            date.with_timezone(&Utc).to_rfc2822()
+        } else {
+            date.to_rfc2822()
+        })
+        .extensions(item_extensions(e, config))
+        .build()
 }
 
-fn replace_img_url(input: &str) -> String {
-    input.replace("{recipe}", "288x288")
+fn series_link(id: &str) -> String {
+    format!("https://www.bbc.co.uk/sounds/series/{}", id)
 }
 
-fn build_rss(id: &str, info: &PodContainer, episodes: &PodEpisodes) -> String {
+/// The channel `<ttl>` in minutes: `--feed-ttl`, or half the time until the
+/// first episode stops being available if that's sooner
+fn feed_ttl(episodes: &PodEpisodes, config: &Config, now: DateTime<Utc>) -> u64 {
+    let soonest = episodes
+        .data
+        .iter()
+        .filter_map(|e| e.available_until())
+        .map(|until| until.with_timezone(&Utc) - now)
+        .filter(|left| *left > chrono::Duration::zero())
+        .min();
+    match soonest {
+        Some(left) => (left.num_minutes() as u64 / 2).max(1).min(config.feed_ttl),
+        None => config.feed_ttl,
+    }
+}
+
+/// The channel for `episodes`, with its namespaces declared
+fn build_channel(
+    id: &str,
+    info: &PodContainer,
+    episodes: &PodEpisodes,
+    config: &Config,
+    opts: &FeedOptions,
+    audio_base: Option<&str>,
+) -> rss::Channel {
+    let series_link = series_link(id);
     let items: Vec<rss::Item> = episodes
         .data
         .iter()
-        .map(|e| {
-            let encl = EnclosureBuilder::default()
-                .mime_type("audio/mpeg".to_string())
-                .length(e.download.quality_variants.high.file_size.to_string())
-                .url(e.download.quality_variants.high.file_url.clone())
-                .build();
-            let itunes_ext = ITunesItemExtensionBuilder::default()
-                .image(replace_img_url(&e.image_url))
-                .duration(Duration::new(e.duration.value, 0).hhmmss())
-                .subtitle(e.synopses.short.clone())
-                .build();
-            ItemBuilder::default()
-                .title(e.titles.secondary.clone())
-                .description(e.synopses.long.clone())
-                .itunes_ext(itunes_ext)
-                .enclosure(encl)
-                .pub_date(
-                    DateTime::parse_from_rfc3339(&e.release.date)
-                        .unwrap_or_else(|_| FixedOffset::east(0).timestamp(0, 0))
-                        .to_rfc2822(),
-                )
-                .build()
-        })
+        .map(|e| build_item(id, info, e, config, opts, audio_base))
         .collect();
     let mut namespaces: BTreeMap<String, String> = BTreeMap::new();
     namespaces.insert(
         "itunes".to_string(),
         "http://www.itunes.com/dtds/podcast-1.0.dtd".to_string(),
     );
-    namespaces.insert(
-        "content".to_string(),
-        "http://purl.org/rss/1.0/modules/content/".to_string(),
-    );
+    if !config.no_content_encoded {
+        namespaces.insert(
+            "content".to_string(),
+            "http://purl.org/rss/1.0/modules/content/".to_string(),
+        );
+    }
+    let owner = config
+        .managing_editor
+        .clone()
+        .and_then(non_empty)
+        .map(|email| {
+            ITunesOwnerBuilder::default()
+                .name("BBC".to_string())
+                .email(email)
+                .build()
+        });
     let itunes_channel = ITunesChannelExtensionBuilder::default()
         .author("BBC".to_string())
+        .owner(owner)
         .block("Yes".to_string())
-        .image(replace_img_url(&info.image_url))
+        .image(non_empty(replace_img_url(
+            &info.image_url,
+            opts.image_size
+                .as_ref()
+                .unwrap_or(&config.channel_image_size),
+        )))
         .complete("No".to_string())
+        .r#type(opts.serial_mode.then(|| "serial".to_string()))
         .build();
-    let channel = ChannelBuilder::default()
+    let mut channel_extensions = if config.podcast_person {
+        podcast_hosts(&info.contributors)
+    } else {
+        Default::default()
+    };
+    // Timelines have no single programme
+    if config.container_pid && !id.is_empty() {
+        let pid = extensions::element("bbc:containerPid", Some(id.to_string()), &[]);
+        extensions::insert(&mut channel_extensions, pid);
+    }
+    if let (Some(url), Some(text)) = (&config.funding_url, &config.funding_text) {
+        let funding = extensions::element("podcast:funding", Some(text.clone()), &[("url", url)]);
+        extensions::insert(&mut channel_extensions, funding);
+    }
+    let description = if config.show_total_duration {
+        let total: u64 = episodes.data.iter().map(|e| e.duration.value).sum();
+        format!(
+            "{}\n\nTotal duration: {}",
+            info.synopses.medium,
+            Duration::from_secs(total).hhmmss()
+        )
+    } else {
+        info.synopses.medium.clone()
+    };
+    let mut channel = ChannelBuilder::default()
         .namespaces(namespaces)
         .title(info.titles.primary.clone())
-        .description(info.synopses.medium.clone())
+        .description(description)
+        .copyright(
+            opts.copyright
+                .clone()
+                .unwrap_or_else(|| config.copyright.clone()),
+        )
+        .docs(config.docs.clone())
+        .managing_editor(config.managing_editor.clone().and_then(non_empty))
+        .webmaster(config.webmaster.clone().and_then(non_empty))
+        .ttl(feed_ttl(episodes, config, config.now()).to_string())
         .itunes_ext(itunes_channel)
-        .link(format!("https://www.bbc.co.uk/sounds/series/{}", id))
+        .link(series_link)
         .items(items)
+        .extensions(channel_extensions)
         .build();
-    channel.to_string()
+    extensions::declare_namespaces(&mut channel);
+    channel
+}
+
+fn build_rss(
+    id: &str,
+    info: &PodContainer,
+    episodes: &PodEpisodes,
+    config: &Config,
+    opts: &FeedOptions,
+    audio_base: Option<&str>,
+) -> String {
+    let channel = build_channel(id, info, episodes, config, opts, audio_base);
+    let xml = write_channel(&channel, opts.pretty);
+    match config.max_response_bytes {
+        Some(max) if xml.len() > max && !episodes.data.is_empty() => {
+            build_rss_within(id, info, episodes, config, opts, audio_base, max)
+        }
+        _ => xml,
+    }
 }
 
-async fn get_feed(path: &str) -> Response<Body> {
-    let id = path[1..].to_string();
-    match try_join!(get_pod_info(&id), get_pod_episodes(&id)) {
-        Ok((PodContainerResponse::Success(info), PodEpisodesResponse::Success(episodes))) => {
-            let rss = build_rss(&id, &info, &episodes);
-            Response::builder()
+/// The feed with only as many of the newest episodes as fit in `max` bytes,
+/// found by binary search on the number kept
+fn build_rss_within(
+    id: &str,
+    info: &PodContainer,
+    episodes: &PodEpisodes,
+    config: &Config,
+    opts: &FeedOptions,
+    audio_base: Option<&str>,
+    max: usize,
+) -> String {
+    let count = episodes.data.len();
+    // How new each episode is, 0 being the newest
+    let mut order: Vec<usize> = (0..count).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(release_date(&episodes.data[i])));
+    let mut rank = vec![0; count];
+    for (r, i) in order.into_iter().enumerate() {
+        rank[i] = r;
+    }
+    let render = |keep: usize| {
+        let mut kept = episodes.clone();
+        let mut ranks = rank.iter();
+        kept.data
+            .retain(|_| ranks.next().is_some_and(|&r| r < keep));
+        let mut channel = build_channel(id, info, &kept, config, opts, audio_base);
+        channel.description.push_str(&format!(
+            "\n\nThis feed has been shortened to its newest {} of {} episodes.",
+            keep, count
+        ));
+        write_channel(&channel, opts.pretty)
+    };
+    // `count` episodes are already known not to fit
+    let (mut lo, mut hi) = (0, count - 1);
+    while lo < hi {
+        let mid = (lo + hi).div_ceil(2);
+        if render(mid).len() <= max {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    let xml = render(lo);
+    if xml.len() > max {
+        log::warn!(
+            "feed for {} is {} bytes even without episodes, over --max-response-bytes",
+            id,
+            xml.len()
+        );
+    } else {
+        log::info!("shortened feed for {} to {} of {} episodes", id, lo, count);
+    }
+    xml
+}
+
+fn write_channel(channel: &rss::Channel, pretty: bool) -> String {
+    if pretty {
+        let buf = channel
+            .pretty_write_to(Vec::new(), b' ', 2)
+            .unwrap_or_default();
+        String::from_utf8(buf).unwrap()
+    } else {
+        channel.to_string()
+    }
+}
+
+/// Public base URL of this proxy: `--base-url`, moved onto the request's
+/// host if that's one of the `--allowed-host`s, or the request's host when
+/// `--base-url` isn't set
+fn base_url(state: &AppState, req: &Request<Body>) -> Option<String> {
+    // HTTP/2 requests carry the host in the URI's authority rather than a
+    // Host header
+    let host = match req.headers().get(header::HOST) {
+        Some(host) => host.to_str().ok(),
+        None => req.uri().authority().map(|a| a.as_str()),
+    };
+    let Some(base) = &state.config.base_url else {
+        return Some(format!("http://{}", host?));
+    };
+    let base = base.trim_end_matches('/');
+    let allowed = host.filter(|host| {
+        state
+            .config
+            .allowed_hosts
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(host))
+    });
+    let rehosted = allowed.and_then(|host| {
+        let authority: Authority = host.parse().ok()?;
+        let mut url = url::Url::parse(base).ok()?;
+        url.set_host(Some(authority.host())).ok()?;
+        url.set_port(authority.port_u16()).ok()?;
+        Some(url.as_str().trim_end_matches('/').to_string())
+    });
+    Some(rehosted.unwrap_or_else(|| base.to_string()))
+}
+
+/// The base of enclosure URLs when they go through the `/audio` endpoint.
+/// Relative URLs keep just the path of `--base-url`, if it has one.
+fn audio_base(state: &AppState, req: &Request<Body>) -> Option<String> {
+    if !state.config.proxy_audio {
+        return None;
+    }
+    match state.config.enclosure_url_mode {
+        EnclosureUrlMode::Absolute => base_url(state, req),
+        EnclosureUrlMode::Relative => Some(
+            state
+                .config
+                .base_url
+                .as_deref()
+                .and_then(|base| url::Url::parse(base).ok())
+                .map(|base| base.path().trim_end_matches('/').to_string())
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// Whether an episode passes the request's `since`, `min_days_left` and
+/// `weekday` filters
+fn matches_filters(e: &PodEpisode, opts: &FeedOptions, now: DateTime<Utc>) -> bool {
+    let date = release_date(e);
+    if opts
+        .since
+        .is_some_and(|since| date.naive_local().date() < since)
+    {
+        return false;
+    }
+    if let Some(days) = opts.min_days_left {
+        // Episodes without an end date are assumed to stay available. A
+        // cutoff past the end of time is one no dated episode lasts until.
+        let cutoff = now.checked_add_signed(chrono::Duration::days(days.into()));
+        if let Some(until) = e.available_until() {
+            if cutoff.is_none_or(|cutoff| until < cutoff) {
+                return false;
+            }
+        }
+    }
+    if let Some(regex) = &opts.filter_regex {
+        let field = match opts.filter_field {
+            FilterField::Title => &e.titles.secondary,
+            FilterField::Description => &e.synopses.long,
+        };
+        if !regex.is_match(field) {
+            return false;
+        }
+    }
+    opts.weekdays
+        .as_ref()
+        .is_none_or(|weekdays| weekdays.contains(&date.weekday()))
+}
+
+/// A rendered feed and whether it was built from an incomplete episode list
+#[derive(Clone)]
+struct RenderedFeed {
+    body: Bytes,
+    /// `body` is gzip-compressed, from `--compress-cache`
+    gzip: bool,
+    partial: bool,
+    /// Filename to offer the body for download as, for CSV
+    attachment: Option<String>,
+    /// Of the uncompressed body
+    etag: String,
+    /// The programme ID BBC redirected the requested one to
+    canonical_id: Option<String>,
+}
+
+/// A feed's ETag: a hash of all of it, or with `--etag-mode weak` of all
+/// but the channel `<ttl>`, which can change with the time alone
+fn etag(body: &str, mode: EtagMode) -> String {
+    match mode {
+        EtagMode::Strong => format!("\"{:016x}\"", fnv1a(body.as_bytes())),
+        EtagMode::Weak => {
+            // The channel's `<ttl>` is written before any item
+            let hash = match (body.find("<ttl>"), body.find("</ttl>")) {
+                (Some(start), Some(end)) if start < end => {
+                    let rest = &body[end + "</ttl>".len()..];
+                    fnv1a(format!("{}{}", &body[..start], rest).as_bytes())
+                }
+                _ => fnv1a(body.as_bytes()),
+            };
+            format!("W/\"{:016x}\"", hash)
+        }
+    }
+}
+
+/// Whether the request's `If-None-Match` names `etag`. The comparison is
+/// weak, as it must be for `If-None-Match`.
+fn etag_matches(req: &Request<Body>, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn gzip(body: &[u8]) -> Bytes {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).unwrap();
+    Bytes::from(encoder.finish().unwrap())
+}
+
+fn gunzip(body: &[u8]) -> Bytes {
+    let mut out = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut out).unwrap();
+    Bytes::from(out)
+}
+
+/// Whether the request's `Accept-Encoding` allows gzip
+fn accepts_gzip(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let rejected = params.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !rejected
+        })
+}
+
+/// Whether the request's `Accept` names HTML, as browsers' do
+fn accepts_html(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| {
+            let name = media.split(';').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case("text/html")
+        })
+}
+
+/// The response for a feed that couldn't be produced. Browsers get a page
+/// with a link to try again when BBC is having trouble; everything else gets
+/// the plain-text error.
+fn feed_error_response(req: &Request<Body>, e: ProxyError) -> Response<Body> {
+    if e.status() != StatusCode::BAD_GATEWAY || !accepts_html(req) {
+        return e.into_response();
+    }
+    let retry = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    e.into_html_response(retry)
+}
+
+async fn render_feed(
+    state: Arc<AppState>,
+    id: String,
+    opts: FeedOptions,
+    audio_base: Option<String>,
+    fallback: Option<PathBuf>,
+) -> Result<RenderedFeed, ProxyError> {
+    let (info, episodes) = join!(
+        bbc::container(&state, &id, opts.cache),
+        bbc::episodes(&state, &id, opts.cache)
+    );
+    // When both fail, report whichever error says more about why
+    if let (Err(a), Err(b)) = (&info, &episodes) {
+        return Err(ProxyError::most_informative(a, b).clone());
+    }
+    let info = info?;
+    // Episodes of a merged programme may only be listed under its new ID
+    let episodes = match (&info.canonical_id, episodes) {
+        (Some(canonical), Err(ProxyError::NotFound(_))) => {
+            bbc::episodes(&state, canonical, opts.cache).await
+        }
+        (_, episodes) => episodes,
+    };
+    // BBC reports a programme without playable episodes as not found, even
+    // though its container exists
+    let mut episodes = match episodes {
+        Err(ProxyError::NotFound(_)) if state.config.empty_on_no_episodes => PodEpisodes::default(),
+        episodes => (*episodes?).clone(),
+    };
+    if state.config.drop_trailers {
+        episodes.data.retain(|e| !is_trailer(e, &state.config));
+    }
+    // Numbered before any per-request filtering so that an episode has the
+    // same number in every view of the feed
+    number_episodes(&mut episodes.data);
+    let now = state.config.now();
+    episodes.data.retain(|e| matches_filters(e, &opts, now));
+    match opts.order {
+        Some(Order::DateAsc) => episodes.data.sort_by_key(release_date),
+        Some(Order::DateDesc) => episodes
+            .data
+            .sort_by_key(|e| std::cmp::Reverse(release_date(e))),
+        Some(Order::DurationAsc) => episodes.data.sort_by_key(|e| e.duration.value),
+        Some(Order::DurationDesc) => episodes
+            .data
+            .sort_by_key(|e| std::cmp::Reverse(e.duration.value)),
+        None => {}
+    }
+    if let Some(limit) = opts.limit {
+        episodes.data.truncate(limit);
+    }
+    let body = match opts.format {
+        Format::Rss => build_rss(
+            &id,
+            &info,
+            &episodes,
+            &state.config,
+            &opts,
+            audio_base.as_deref(),
+        ),
+        Format::Ics => ics::build_ics(&id, &info, &episodes),
+        Format::Csv => csv::build_csv(&episodes, &opts, audio_base.as_deref()),
+    };
+    // A partial feed would replace a complete one that may still be useful
+    if let (Some(path), false) = (&fallback, episodes.partial) {
+        save_fallback(&state.config, path, body.as_bytes()).await;
+    }
+    let compress = state.config.compress_cache;
+    let etag = etag(&body, state.config.etag_mode);
+    Ok(RenderedFeed {
+        body: if compress {
+            gzip(body.as_bytes())
+        } else {
+            Bytes::from(body)
+        },
+        gzip: compress,
+        partial: episodes.partial,
+        attachment: (opts.format == Format::Csv).then(|| csv::filename(&info.titles.primary, &id)),
+        etag,
+        canonical_id: info.canonical_id.clone(),
+    })
+}
+
+/// A parsed feed path: `/{id}`, `/{id}.ics`, `/{id}.csv` or
+/// `/{id}/since/{date}`
+struct FeedPath<'a> {
+    id: &'a str,
+    since: Option<&'a str>,
+    format: Format,
+}
+
+/// Split a feed path into its parts. One trailing slash is ignored, so
+/// `/{id}/` is the same feed as `/{id}`.
+fn parse_feed_path(path: &str) -> Option<FeedPath<'_>> {
+    let path = feed_path(path);
+    let segments: Vec<&str> = path[1..].split('/').collect();
+    let (id, since) = match segments[..] {
+        [id] => (id, None),
+        [id, "since", date] => (id, Some(date)),
+        _ => return None,
+    };
+    let (id, format) = if let Some(id) = id.strip_suffix(".ics") {
+        (id, Format::Ics)
+    } else if let Some(id) = id.strip_suffix(".csv") {
+        (id, Format::Csv)
+    } else {
+        (id, Format::Rss)
+    };
+    Some(FeedPath { id, since, format })
+}
+
+/// Where `--fallback-feed-dir` keeps the copy of feed `id` rendered with
+/// `opts` and `audio_base`. The file is named for the parsed options rather
+/// than the query string, so that parameters the proxy ignores, or the
+/// order they're given in, don't each make another copy.
+fn fallback_path(dir: &Path, id: &str, audio_base: Option<&str>, opts: &FeedOptions) -> PathBuf {
+    let opts = FeedOptions {
+        cache: CachePolicy::default(),
+        ..opts.clone()
+    };
+    let key = format!("{}{:?}", audio_base.unwrap_or_default(), opts);
+    let extension = match opts.format {
+        Format::Rss => "xml",
+        Format::Ics => "ics",
+        Format::Csv => "csv",
+    };
+    dir.join(format!(
+        "{}-{:016x}.{}",
+        id,
+        fnv1a(key.as_bytes()),
+        extension
+    ))
+}
+
+/// Write a fallback feed via a temporary file, so that it is never read
+/// half-written, then trim the directory to `--fallback-feed-dir-size`
+async fn save_fallback(config: &Config, path: &Path, body: &[u8]) {
+    let tmp = path.with_extension("tmp");
+    let result = match tokio::fs::write(&tmp, body).await {
+        Ok(()) => tokio::fs::rename(&tmp, path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        log::warn!("failed to save fallback feed {}: {}", path.display(), e);
+        return;
+    }
+    if let Some(dir) = path.parent() {
+        let max_bytes = config.fallback_feed_dir_size * 1_000_000;
+        if let Err(e) = prune_fallbacks(dir, max_bytes).await {
+            log::warn!("failed to trim fallback feeds in {}: {}", dir.display(), e);
+        }
+    }
+}
+
+/// Remove the least recently written feeds in `dir` until the rest total at
+/// most `max_bytes`
+async fn prune_fallbacks(dir: &Path, max_bytes: u64) -> std::io::Result<()> {
+    let mut feeds = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let extension = path.extension().and_then(|e| e.to_str());
+        if !matches!(extension, Some("xml" | "ics" | "csv")) {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+        feeds.push((metadata.modified()?, metadata.len(), path));
+    }
+    let mut total: u64 = feeds.iter().map(|(_, len, _)| len).sum();
+    feeds.sort();
+    for (_, len, path) in feeds {
+        if total <= max_bytes {
+            break;
+        }
+        // Another request may have just removed it
+        let _ = tokio::fs::remove_file(&path).await;
+        total -= len;
+    }
+    Ok(())
+}
+
+/// A feed request's programme ID and options, taken apart from its path and
+/// query string
+struct FeedRequest {
+    id: String,
+    opts: FeedOptions,
+}
+
+fn decode_segment(segment: &str) -> Result<String, ProxyError> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .map(String::from)
+        .map_err(|_| ProxyError::BadRequest(format!("Invalid path segment {:?}", segment)))
+}
+
+impl FeedRequest {
+    fn parse(
+        path: FeedPath<'_>,
+        query: Option<&str>,
+        overrides: &Overrides,
+    ) -> Result<Self, ProxyError> {
+        let id = decode_segment(path.id)?;
+        // The request's parameters follow the programme's overrides, so that
+        // they win
+        let query = match (overrides.get(&id), query) {
+            (Some(defaults), Some(query)) => Some(format!("{}&{}", defaults, query)),
+            (Some(defaults), None) => Some(defaults.clone()),
+            (None, query) => query.map(String::from),
+        };
+        let mut opts = FeedOptions::from_query(query.as_deref())?;
+        opts.format = path.format;
+        if let Some(date) = path.since {
+            opts.since = Some(options::parse_date(&decode_segment(date)?)?);
+        }
+        Ok(FeedRequest { id, opts })
+    }
+}
+
+/// Longest programme ID accepted. BBC PIDs are 8 to 15 characters.
+const MAX_ID_LEN: usize = 32;
+
+/// Whether `id` could be a programme ID, which keeps anything that could
+/// alter the upstream URL, such as percent-encoded slashes or dots, out of it
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN && id.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// Reject IDs that are malformed or blocked by `--allow-ids`/`--deny-ids`
+fn check_id(state: &AppState, id: &str) -> Result<(), ProxyError> {
+    if !is_valid_id(id) {
+        return Err(ProxyError::BadRequest("Invalid programme ID".to_string()));
+    }
+    if !state.config.is_id_allowed(id) {
+        return Err(ProxyError::Forbidden(format!(
+            "Feeds for {} are not available",
+            id
+        )));
+    }
+    Ok(())
+}
+
+/// The cache policy asked for by a `Cache-Control: no-cache` or `no-store`
+/// request header
+fn request_cache_policy(req: &Request<Body>) -> Option<CachePolicy> {
+    let directives: Vec<String> = req
+        .headers()
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect();
+    if directives.iter().any(|d| d == "no-store") {
+        Some(CachePolicy::NoStore)
+    } else if directives.iter().any(|d| d == "no-cache") {
+        Some(CachePolicy::Refresh)
+    } else {
+        None
+    }
+}
+
+/// A feed's path without the trailing slash it may have been requested with
+fn feed_path(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(path) if !path.is_empty() => path,
+        _ => path,
+    }
+}
+
+/// Feed cache key: the base URL and request URI without `nocache`, so that
+/// a forced refresh replaces the entry other requests are served from
+fn feed_cache_key(audio_base: Option<&str>, req: &Request<Body>) -> String {
+    let path = feed_path(req.uri().path());
+    let mut key = format!("{}{}", audio_base.unwrap_or_default(), path);
+    let query = req.uri().query().unwrap_or_default();
+    let pairs = form_urlencoded::parse(query.as_bytes()).filter(|(k, _)| k != "nocache");
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish();
+    if !query.is_empty() {
+        key.push('?');
+        key.push_str(&query);
+    }
+    key
+}
+
+async fn get_feed(
+    state: &Arc<AppState>,
+    req: &Request<Body>,
+    path: FeedPath<'_>,
+) -> Response<Body> {
+    Metrics::inc(&state.metrics.feed_requests);
+    let FeedRequest { id, mut opts } =
+        match FeedRequest::parse(path, req.uri().query(), &state.overrides) {
+            Ok(feed) => feed,
+            Err(e) => return e.into_response(),
+        };
+    if let Err(e) = check_id(state, &id) {
+        return e.into_response();
+    }
+    if let Err(e) = opts.check_image_size(&state.config.image_recipes) {
+        return e.into_response();
+    }
+    let audio_base = audio_base(state, req);
+    if let Some(policy) = request_cache_policy(req) {
+        opts.cache = policy;
+    }
+    // Streamed feeds aren't cached, so in maintenance mode there's nothing
+    // to serve them from
+    if stream::can_stream(&state.config, &opts) && !state.in_maintenance() {
+        return stream::stream_feed(state.clone(), id, opts, audio_base)
+            .await
+            .unwrap_or_else(|e| feed_error_response(req, e));
+    }
+    let policy = opts.cache;
+    let key = feed_cache_key(audio_base.as_deref(), req);
+    let fallback = state
+        .config
+        .fallback_feed_dir
+        .as_deref()
+        .map(|dir| fallback_path(dir, &id, audio_base.as_deref(), &opts));
+    let fetch = || {
+        render_feed(
+            state.clone(),
+            id.clone(),
+            opts.clone(),
+            audio_base.clone(),
+            fallback.clone(),
+        )
+    };
+    let (mut result, mut cache) = if state.in_maintenance() {
+        let cached = state.feeds.peek(&key);
+        match cached.filter(|_| state.config.maintenance_serve_cached) {
+            Some((feed, cache)) => (Ok(feed), cache),
+            None => return maintenance_response(state),
+        }
+    } else {
+        state.feeds.get_or_fetch(&key, policy, fetch).await
+    };
+    // Empty responses from BBC are usually transient, so they get one retry
+    if let Err(ProxyError::EmptyResponse) = result {
+        log::warn!("retrying {} after an empty response from BBC", id);
+        (result, cache) = state.feeds.get_or_fetch(&key, policy, fetch).await;
+    }
+    let mut from_fallback = false;
+    if let (Err(e), Some(path)) = (&result, &fallback) {
+        if e.status() == StatusCode::BAD_GATEWAY {
+            if let Ok(body) = tokio::fs::read(path).await {
+                log::warn!("serving fallback feed for {} after fetch failed: {}", id, e);
+                let etag = etag(&String::from_utf8_lossy(&body), state.config.etag_mode);
+                result = Ok(RenderedFeed {
+                    body: Bytes::from(body),
+                    gzip: false,
+                    partial: false,
+                    attachment: (opts.format == Format::Csv).then(|| format!("{}.csv", id)),
+                    etag,
+                    canonical_id: None,
+                });
+                from_fallback = true;
+            }
+        }
+    }
+    Metrics::inc(match cache.status {
+        CacheStatus::Hit => &state.metrics.cache_hits,
+        CacheStatus::Miss => &state.metrics.cache_misses,
+        CacheStatus::Coalesced => &state.metrics.cache_coalesced,
+        CacheStatus::Stale => &state.metrics.cache_stale,
+    });
+    let mut response = match result {
+        Ok(feed) => {
+            let mut builder = Response::builder()
                 .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, "application/rss+xml")
-                .body(Body::from(rss))
-                .unwrap()
+                .header(header::CONTENT_TYPE, opts.format.content_type());
+            if feed.partial {
+                builder = builder.header("X-Partial", "true");
+            }
+            if let Some(canonical) = feed
+                .canonical_id
+                .as_deref()
+                .filter(|_| state.config.canonical_id_header)
+            {
+                builder = builder.header("X-Canonical-Id", canonical);
+            }
+            if let Some(filename) = &feed.attachment {
+                builder = builder.header(
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", filename),
+                );
+            }
+            if from_fallback {
+                builder = builder.header(header::WARNING, "110 soundsproxy \"Response is Stale\"");
+            }
+            // A strong ETag has to differ between content codings
+            let etag = if feed.gzip && accepts_gzip(req) && feed.etag.starts_with('"') {
+                format!("{}-gzip\"", feed.etag.trim_end_matches('"'))
+            } else {
+                feed.etag.clone()
+            };
+            if etag_matches(req, &etag) {
+                let mut not_modified = Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .header(header::ETAG, &etag);
+                if feed.gzip {
+                    not_modified = not_modified.header(header::VARY, "Accept-Encoding");
+                }
+                return not_modified.body(Body::empty()).unwrap();
+            }
+            builder = builder.header(header::ETAG, &etag);
+            let body = if !feed.gzip {
+                feed.body
+            } else if accepts_gzip(req) {
+                builder = builder.header(header::CONTENT_ENCODING, "gzip");
+                feed.body
+            } else {
+                gunzip(&feed.body)
+            };
+            if feed.gzip {
+                builder = builder.header(header::VARY, "Accept-Encoding");
+            }
+            // Ranges only make sense of a body that's sent as it is
+            let body = if feed.gzip && accepts_gzip(req) {
+                body
+            } else {
+                builder = builder.header(header::ACCEPT_RANGES, "bytes");
+                let len = body.len() as u64;
+                match parse_range(req.headers().get(header::RANGE), len) {
+                    Ok(Some((start, end))) => {
+                        builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                            header::CONTENT_RANGE,
+                            format!("bytes {}-{}/{}", start, end, len),
+                        );
+                        body.slice(start as usize..=end as usize)
+                    }
+                    Ok(None) => body,
+                    Err(()) => {
+                        return Response::builder()
+                            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                            .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                            .body(Body::empty())
+                            .unwrap()
+                    }
+                }
+            };
+            builder.body(Body::from(body)).unwrap()
         }
-        Ok((PodContainerResponse::Failure(err), _))
-        | Ok((_, PodEpisodesResponse::Failure(err))) => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from(err.errors[0].message.clone()))
-            .unwrap(),
-        Err(e) => Response::builder()
-            .status(StatusCode::BAD_GATEWAY)
-            .body(Body::from(e.to_string()))
-            .unwrap(),
+        Err(e) => return feed_error_response(req, e),
+    };
+    if state.config.debug_headers {
+        let label = match cache.status {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss | CacheStatus::Coalesced => "MISS",
+            CacheStatus::Stale => "STALE",
+        };
+        let headers = response.headers_mut();
+        headers.insert("X-Cache", header::HeaderValue::from_static(label));
+        headers.insert("X-Cache-Age", cache.age.as_secs().into());
     }
+    response
+}
+
+/// The 503 for requests that would reach BBC in maintenance mode
+fn maintenance_response(state: &AppState) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(state.config.maintenance_message.clone()))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .unwrap()
 }
 
-async fn router(req: Request<Body>) -> ApiResult<Response<Body>> {
+/// Operational endpoints, served by the main router unless `--metrics-addr`
+/// gives them a listener of their own
+fn ops_route(req: &Request<Body>, state: &AppState) -> Option<Response<Body>> {
     match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Some(Response::new(state.metrics.render().into())),
+        (&Method::GET, "/health") => Some(Response::new("OK".into())),
+        _ => None,
+    }
+}
+
+/// Add the `--response-header` headers to a response
+fn add_response_headers(state: &AppState, response: &mut Response<Body>) {
+    for h in &state.config.response_headers {
+        response
+            .headers_mut()
+            .insert(h.name.clone(), h.value.clone());
+    }
+}
+
+async fn ops_router(req: Request<Body>, state: Arc<AppState>) -> ApiResult<Response<Body>> {
+    let mut response = ops_route(&req, &state).unwrap_or_else(not_found);
+    add_response_headers(&state, &mut response);
+    Ok(response)
+}
+
+async fn router(
+    req: Request<Body>,
+    state: Arc<AppState>,
+    remote: SocketAddr,
+) -> ApiResult<Response<Body>> {
+    let mut response = route(req, state.clone(), remote).await?;
+    add_response_headers(&state, &mut response);
+    Ok(response)
+}
+
+async fn route(
+    req: Request<Body>,
+    state: Arc<AppState>,
+    remote: SocketAddr,
+) -> ApiResult<Response<Body>> {
+    // None of our routes read a request body, so for `Expect: 100-continue` we
+    // answer straight away with the final response rather than a 100, which
+    // hyper would only send once the body is polled. Other expectations can't
+    // be met.
+    if let Some(expect) = req.headers().get(header::EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            return Ok(Response::builder()
+                .status(StatusCode::EXPECTATION_FAILED)
+                .body(Body::empty())
+                .unwrap());
+        }
+    }
+    if state.config.metrics_addr.is_none() {
+        if let Some(response) = ops_route(&req, &state) {
+            return Ok(response);
+        }
+    }
+    // Health checks and metrics aren't limited, so they answer however busy
+    // the proxy is
+    let max = state.config.max_concurrent_per_ip;
+    if max == 0 {
+        return route_feeds(req, state).await;
+    }
+    let client = limit::client_ip(remote.ip(), req.headers(), state.config.trusted_proxy_count);
+    let slot = match state.concurrency.acquire(client, max) {
+        Some(slot) => slot,
+        None => {
+            log::info!("{} is over its concurrent request limit", client);
+            return Ok(ProxyError::TooManyRequests.into_response());
+        }
+    };
+    let response = route_feeds(req, state).await?;
+    Ok(hold_until_sent(response, slot))
+}
+
+/// Keep `guard` until `response` has been sent. Streamed bodies, such as
+/// proxied audio, are still being sent after the handler returns; others are
+/// already complete, so they drop it straight away.
+fn hold_until_sent<T: Send + Sync + 'static>(response: Response<Body>, guard: T) -> Response<Body> {
+    if response.body().size_hint().exact().is_some() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = body.map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// Whether a GET for `path` would be routed anywhere but a 404, so that
+/// OPTIONS is only answered for paths that exist
+fn is_route(state: &AppState, path: &str) -> bool {
+    match path {
+        "/" | "/audio" | "/resolve" | "/validate-ids" | "/timeline" => true,
+        "/metrics" | "/health" => state.config.metrics_addr.is_none(),
+        _ => match path.strip_prefix("/info/") {
+            Some(rest) => rest.ends_with(".json"),
+            None => parse_feed_path(path).is_some(),
+        },
+    }
+}
+
+/// The routes for everything but `/health` and `/metrics`
+async fn route_feeds(req: Request<Body>, state: Arc<AppState>) -> ApiResult<Response<Body>> {
+    // HEAD is routed as GET; hyper drops the body
+    let method = match req.method() {
+        &Method::HEAD => &Method::GET,
+        method => method,
+    };
+    match (method, req.uri().path()) {
+        (&Method::OPTIONS, path) if is_route(&state, path) => Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ALLOW, "GET, HEAD, OPTIONS")
+            .body(Body::empty())
+            .unwrap()),
+        (&Method::OPTIONS, _) => Ok(not_found()),
         (&Method::GET, "/") => Ok(Response::new("Hello, World".into())),
-        (&Method::GET, p) => Ok(get_feed(p).await),
+        // Feeds are left to `get_feed`, which may serve them from the cache
+        (&Method::GET, "/audio" | "/validate-ids" | "/timeline") if state.in_maintenance() => {
+            Ok(maintenance_response(&state))
+        }
+        (&Method::GET, path) if path.starts_with("/info/") && state.in_maintenance() => {
+            Ok(maintenance_response(&state))
+        }
+        (&Method::GET, "/audio") => Ok(audio::get_audio(&state, &req).await),
+        (&Method::GET, "/resolve") => Ok(resolve::get_resolve(&state, &req)),
+        (&Method::GET, "/validate-ids") => Ok(validate::get_validate_ids(&state, &req).await),
+        (&Method::GET, "/timeline") => Ok(timeline::get_timeline(&state, &req).await),
+        // Moved to the `--metrics-addr` listener
+        (&Method::GET, "/metrics" | "/health") => Ok(not_found()),
+        (&Method::GET, path) if path.starts_with("/info/") => {
+            match path["/info/".len()..].strip_suffix(".json") {
+                Some(id) => Ok(info::get_info(&state, id).await),
+                None => Ok(not_found()),
+            }
+        }
+        (&Method::GET, path) => match parse_feed_path(path) {
+            Some(path) => {
+                let _in_flight = state.metrics.track_in_flight();
+                Ok(get_feed(&state, &req, path).await)
+            }
+            None => Ok(not_found()),
+        },
         (_, _) => Ok(Response::new("Hello, World".into())),
     }
 }
 
+/// Bind the listening socket ourselves so that the accept backlog can be set
+fn bind_listener(addr: SocketAddr, backlog: i32) -> std::io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// The feed server on `listener`, speaking HTTP/1.x and, with `http2`, h2c
+fn server(
+    listener: std::net::TcpListener,
+    tcp_nodelay: bool,
+    http2: bool,
+) -> hyper::server::Builder<hyper::server::conn::AddrIncoming> {
+    Server::from_tcp(listener)
+        .unwrap()
+        .tcp_nodelay(tcp_nodelay)
+        .http1_only(!http2)
+}
+
+/// Switch maintenance mode on and off on each SIGHUP
+#[cfg(unix)]
+async fn toggle_maintenance_on_hangup(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::warn!("failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let on = !state.maintenance.fetch_xor(true, Ordering::Relaxed);
+        log::info!("maintenance mode {}", if on { "on" } else { "off" });
+    }
+}
+
 async fn shutdown_signal() {
     // Wait for the CTRL+C signal
     tokio::signal::ctrl_c()
@@ -241,23 +1444,2194 @@ async fn shutdown_signal() {
         .expect("failed to install CTRL+C signal handler");
 }
 
+/// Log the number of in-flight requests as they drain after shutdown starts,
+/// returning once `timeout` has passed
+async fn report_drain(state: Arc<AppState>, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut remaining = state.metrics.in_flight();
+    while Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let now = state.metrics.in_flight();
+        if now != remaining {
+            log::info!("{} requests still in flight", now);
+            remaining = now;
+        }
+    }
+    log::warn!(
+        "shutdown timed out after {:?} with {} requests in flight",
+        timeout,
+        remaining
+    );
+}
+
+/// The clients for requests to the BBC API and for proxied audio
+fn http_clients(config: &Config) -> reqwest::Result<(reqwest::Client, reqwest::Client)> {
+    let builder = || {
+        reqwest::Client::builder()
+            .user_agent("soundsproxy/0.1")
+            .min_tls_version(config.min_tls_version.to_reqwest())
+    };
+    // reqwest counts the original request as well as the redirects
+    let api = builder()
+        .redirect(reqwest::redirect::Policy::limited(config.max_redirects + 1))
+        .build()?;
+    let audio = builder().redirect(audio::redirect_policy()).build()?;
+    Ok((api, audio))
+}
+
 #[tokio::main]
 async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let config = Config::parse();
+    if let Err(e) = config.check_image_recipes() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+    let overrides = match &config.overrides {
+        Some(path) => match overrides::load(path) {
+            Ok(overrides) => overrides,
+            Err(e) => {
+                log::error!("invalid overrides in {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => Overrides::new(),
+    };
     let addr = SocketAddr::from(([127, 0, 0, 1], PORT));
-    // let client = reqwest::Client::builder()
-    //     .user_agent("soundsproxy/0.1")
-    //     .build()
-    //     .unwrap();
-
-    let svc = make_service_fn(move |_| {
-        // let c = client.clone();
-        async { Ok::<_, GenericError>(service_fn(move |req| router(req))) }
+    let listener = match bind_listener(addr, config.listen_backlog) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("failed to listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(dir) = &config.fallback_feed_dir {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            log::error!("failed to create {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+    let audio_cache = config.audio_cache_dir.as_ref().map(|dir| {
+        match AudioCache::open(dir, config.audio_cache_size * 1_000_000) {
+            Ok(cache) => Arc::new(cache),
+            Err(e) => {
+                log::error!("failed to open audio cache in {}: {}", dir.display(), e);
+                std::process::exit(1);
+            }
+        }
+    });
+    let ops_listener =
+        config
+            .metrics_addr
+            .map(|addr| match bind_listener(addr, config.listen_backlog) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    log::error!("failed to listen on {} for metrics: {}", addr, e);
+                    std::process::exit(1);
+                }
+            });
+    let tcp_nodelay = config.tcp_nodelay;
+    let http2 = config.http2;
+    let (client, audio_client) = match http_clients(&config) {
+        Ok(clients) => clients,
+        Err(e) => {
+            log::error!("failed to create HTTP client: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let state = Arc::new(AppState::new(
+        config,
+        client,
+        audio_client,
+        audio_cache,
+        overrides,
+    ));
+
+    log::info!(
+        "listening on {} (backlog {}, TCP_NODELAY {}, HTTP/2 {}), BBC API {} (TLS {}+), cache TTL {}s",
+        addr,
+        state.config.listen_backlog,
+        state.config.tcp_nodelay,
+        state.config.http2,
+        state.config.api_url(),
+        state.config.min_tls_version,
+        state.config.cache_ttl
+    );
+    if state.config.startup_check {
+        let id = &state.config.startup_check_id;
+        match bbc::self_check(&state, id).await {
+            Ok(()) => log::info!("startup check against {} passed", state.config.api_url()),
+            Err(e) if state.config.fail_fast => {
+                log::error!("startup check for {} failed: {}", id, e);
+                std::process::exit(1);
+            }
+            Err(e) => log::warn!("startup check for {} failed, starting anyway: {}", id, e),
+        }
+    }
+
+    if state.in_maintenance() {
+        log::info!("starting in maintenance mode");
+    }
+    #[cfg(unix)]
+    tokio::spawn(toggle_maintenance_on_hangup(state.clone()));
+
+    if let Some(ops_listener) = ops_listener {
+        let state = state.clone();
+        let svc = make_service_fn(move |_| {
+            let state = state.clone();
+            async move { Ok::<_, GenericError>(service_fn(move |req| ops_router(req, state.clone()))) }
+        });
+        let srv = Server::from_tcp(ops_listener).unwrap().serve(svc);
+        log::info!("serving metrics on {}", srv.local_addr());
+        tokio::spawn(async move {
+            if let Err(e) = srv.await {
+                log::error!("metrics server error: {}", e);
+            }
+        });
+    }
+
+    let shutdown_timeout = Duration::from_secs(state.config.shutdown_timeout);
+    let drain_state = state.clone();
+    let svc = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let remote = conn.remote_addr();
+        async move { Ok::<_, GenericError>(service_fn(move |req| router(req, state.clone(), remote))) }
     });
-    let srv = Server::bind(&addr).serve(svc);
+    let srv = server(listener, tcp_nodelay, http2).serve(svc);
 
-    let graceful = srv.with_graceful_shutdown(shutdown_signal());
+    let (draining_tx, draining_rx) = tokio::sync::oneshot::channel();
+    let signal_state = drain_state.clone();
+    let flush_state = drain_state.clone();
+    let graceful = srv.with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        log::info!(
+            "shutting down, {} requests in flight",
+            signal_state.metrics.in_flight()
+        );
+        let _ = draining_tx.send(());
+    });
+    let drain = async {
+        match draining_rx.await {
+            Ok(()) => report_drain(drain_state, shutdown_timeout).await,
+            // The server stopped by itself, so there's nothing to drain
+            Err(_) => futures::future::pending().await,
+        }
+    };
+
+    let shutdown = async {
+        if let Err(e) = graceful.await {
+            log::error!("server error: {}", e);
+        }
+        // Feeds whose requests went away are still being rendered. Let them
+        // finish, within the same timeout, so they're written to
+        // `--fallback-feed-dir` for the next run.
+        if flush_state.config.fallback_feed_dir.is_some() {
+            let count = flush_state.feeds.settle().await;
+            if count > 0 {
+                log::info!("finished {} feed renders before exiting", count);
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = shutdown => {}
+        _ = drain => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{get, text, MockBbc, Upstream, PROGRAMME};
+
+    /// An episode released on `date` and, if `until` is given, available
+    /// until then
+    fn episode(date: &str, until: Option<&str>) -> PodEpisode {
+        let variant = serde_json::json!({
+            "bitrate": 128,
+            "file_url": "https://example.com/a.mp3",
+            "file_size": 1000,
+            "label": "High",
+        });
+        serde_json::from_value(serde_json::json!({
+            "id": "p0000001",
+            "titles": { "primary": "Programme", "secondary": "Episode" },
+            "synopses": { "short": "", "medium": "", "long": "" },
+            "image_url": "",
+            "duration": { "value": 60, "label": "1 min" },
+            "download": {
+                "type": "non-drm",
+                "quality_variants": { "low": variant, "medium": variant, "high": variant },
+            },
+            "release": { "date": date, "label": "" },
+            "availability": { "to": until },
+        }))
+        .unwrap()
+    }
+
+    /// Episode `e`'s `<item>` as configured by command-line `args` and
+    /// requested with `query`
+    fn item(args: &[&str], e: &PodEpisode, query: &str) -> rss::Item {
+        let mut argv = vec!["soundsproxy"];
+        argv.extend_from_slice(args);
+        let config = Config::try_parse_from(argv).unwrap();
+        let opts = FeedOptions::from_query(Some(query)).unwrap();
+        build_item(PROGRAMME, &testing::container(), e, &config, &opts, None)
+    }
+
+    #[test]
+    fn episodes_without_a_synopsis_get_a_description() {
+        let mut e = testing::episode(1);
+        e.synopses = bbc::PodSynopses {
+            short: String::new(),
+            medium: String::new(),
+            long: String::new(),
+        };
+        let description = |args: &[&str], e: &PodEpisode| item(args, e, "").description.unwrap();
+        assert_eq!(description(&[], &e), "Episode 1");
+        assert_eq!(
+            description(&["--empty-synopsis-placeholder", "No description"], &e),
+            "No description"
+        );
+        e.synopses.long = "Long".to_string();
+        assert_eq!(description(&[], &e), "Long");
+    }
+
+    fn variants(low: u32, medium: u32, high: u32) -> bbc::PodQualityVariants {
+        let variant = |label: &str, file_size| bbc::PodQualityVariant {
+            bitrate: 0,
+            file_url: String::new(),
+            file_size,
+            label: label.to_string(),
+        };
+        bbc::PodQualityVariants {
+            low: variant("Low", low),
+            medium: variant("Medium", medium),
+            high: variant("High", high),
+        }
+    }
+
+    #[test]
+    fn best_variant_under_max_size_is_chosen() {
+        let pick = |variants: &bbc::PodQualityVariants, max_size| {
+            let opts = FeedOptions {
+                max_size,
+                ..FeedOptions::default()
+            };
+            select_variant(variants, &opts).label.clone()
+        };
+        let usual = variants(10, 20, 30);
+        assert_eq!(pick(&usual, None), "High");
+        assert_eq!(pick(&usual, Some(30)), "High");
+        assert_eq!(pick(&usual, Some(29)), "Medium");
+        assert_eq!(pick(&usual, Some(10)), "Low");
+        // Nothing fits, so the smallest
+        assert_eq!(pick(&usual, Some(5)), "Low");
+        // Quality wins over size among those that fit
+        let odd = variants(30, 10, 20);
+        assert_eq!(pick(&odd, Some(25)), "High");
+        assert_eq!(pick(&odd, Some(15)), "Medium");
+        assert_eq!(pick(&odd, Some(5)), "Medium");
+        let same = variants(10, 10, 10);
+        assert_eq!(pick(&same, Some(5)), "High");
+    }
+
+    #[test]
+    fn phrases_match_whole_words_only() {
+        assert!(contains_phrase("Series 2 Trailer", "trailer"));
+        assert!(contains_phrase("TRAILER: Series 2", "Trailer"));
+        assert!(contains_phrase("Coming soon!", "Coming Soon"));
+        assert!(!contains_phrase("Trailers for Sale", "Trailer"));
+        assert!(!contains_phrase("The Trailerpark Boys", "Trailer"));
+        assert!(!contains_phrase("Becoming Soonest", "Coming Soon"));
+        assert!(!contains_phrase("Anything", ""));
+    }
+
+    /// The titles of `feed`'s items
+    fn titles(feed: &str) -> Vec<String> {
+        let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+        channel
+            .items
+            .iter()
+            .map(|i| i.title.clone().unwrap_or_default())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn trailers_are_dropped_from_feeds() {
+        let mut upstream = Upstream::default();
+        let names = [
+            "Series 2 Trailer",
+            "Trailers for Sale",
+            "Coming Soon",
+            "Episode 4",
+        ];
+        upstream.episodes = (1..=4)
+            .map(|n| {
+                let mut e = testing::episode_json(n);
+                e["titles"]["secondary"] = names[n - 1].into();
+                e
+            })
+            .collect();
+        let bbc = MockBbc::start(upstream).await;
+        let path = format!("/{}", PROGRAMME);
+        let all = text(get(&testing::state(&bbc, &[]), &path).await).await;
+        assert_eq!(titles(&all), names);
+        let state = testing::state(&bbc, &["--drop-trailers"]);
+        let kept = text(get(&state, &path).await).await;
+        assert_eq!(titles(&kept), ["Trailers for Sale", "Episode 4"]);
+    }
+
+    #[test]
+    fn items_link_to_their_episode() {
+        let mut e = testing::episode(1);
+        let link = |args: &[&str], e: &PodEpisode| item(args, e, "").link.unwrap();
+        assert_eq!(link(&[], &e), "https://www.bbc.co.uk/sounds/play/e0000001");
+        assert_eq!(
+            link(&["--item-link-base", "https://example.com/play/"], &e),
+            "https://example.com/play/e0000001"
+        );
+        assert!(url::Url::parse(&link(&[], &e)).is_ok());
+        // Without a PID, the programme's page
+        e.id.clear();
+        assert_eq!(link(&[], &e), series_link(PROGRAMME));
+    }
+
+    #[test]
+    fn guids_come_from_the_configured_source() {
+        let mut e = testing::episode(1);
+        let guid = |source, e: &PodEpisode| {
+            let guid = super::guid(PROGRAMME, e, source);
+            assert!(!guid.is_permalink());
+            guid.value
+        };
+        // Pinned, since GUIDs must not change between builds
+        let hash = "00dc198ec2aab419";
+        assert_eq!(guid(GuidSource::Pid, &e), "e0000001");
+        assert_eq!(guid(GuidSource::Hash, &e), hash);
+        assert_eq!(
+            guid(GuidSource::Url, &e),
+            "https://open.live.bbc.co.uk/High/1.mp3"
+        );
+        // Episodes without a PID fall back to the hash
+        e.id.clear();
+        assert_eq!(guid(GuidSource::Pid, &e), hash);
+        let item = item(&["--guid-source", "url"], &testing::episode(1), "");
+        assert_eq!(
+            item.guid.unwrap().value,
+            "https://open.live.bbc.co.uk/High/1.mp3"
+        );
+    }
+
+    #[tokio::test]
+    async fn episodes_can_be_sorted_by_duration() {
+        let mut upstream = Upstream::default();
+        for (e, seconds) in upstream.episodes.iter_mut().zip([300, 100, 200]) {
+            e["duration"]["value"] = seconds.into();
+        }
+        let bbc = MockBbc::start(upstream).await;
+        let state = testing::state(&bbc, &[]);
+        let order = |query: &'static str| {
+            let state = state.clone();
+            async move {
+                let feed = text(get(&state, &format!("/{}?{}", PROGRAMME, query)).await).await;
+                titles(&feed)
+            }
+        };
+        assert_eq!(
+            order("order=duration_asc").await,
+            ["Episode 2", "Episode 3", "Episode 1"]
+        );
+        assert_eq!(
+            order("order=duration_desc").await,
+            ["Episode 1", "Episode 3", "Episode 2"]
+        );
+        assert_eq!(
+            order("order=duration_asc&limit=2").await,
+            ["Episode 2", "Episode 3"]
+        );
+        let response = get(&state, &format!("/{}?order=shortest", PROGRAMME)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn copyright_is_configurable_and_overridable() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let copyright = |args: &'static [&'static str], query: &'static str| {
+            let state = testing::state(&bbc, args);
+            async move {
+                let feed = text(get(&state, &format!("/{}{}", PROGRAMME, query)).await).await;
+                let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+                channel.copyright.unwrap_or_default()
+            }
+        };
+        assert_eq!(copyright(&[], "").await, "© BBC");
+        assert_eq!(copyright(&["--copyright", "© Me"], "").await, "© Me");
+        assert_eq!(
+            copyright(&["--copyright", "© Me"], "?copyright=%C2%A9%20You").await,
+            "© You"
+        );
+    }
+
+    #[test]
+    fn release_label_starts_the_description_when_asked() {
+        let e = testing::episode(1);
+        let description = |query| item(&[], &e, query).description.unwrap();
+        assert_eq!(description(""), "Long 1");
+        assert_eq!(
+            description("show_release_label=true"),
+            "Mon 1 Jan 2024\nLong 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn synthesized_numbers_count_up_from_the_oldest_episode() {
+        let numbers = |count: usize| async move {
+            // BBC lists the newest first
+            let bbc = MockBbc::start(Upstream {
+                episodes: (1..=count).rev().map(testing::episode_json).collect(),
+                ..Upstream::default()
+            })
+            .await;
+            let state = testing::state(&bbc, &["--synthesize-episode-numbers"]);
+            let feed = text(get(&state, &format!("/{}", PROGRAMME)).await).await;
+            let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+            channel
+                .items
+                .iter()
+                .map(|i| {
+                    let title = i.title.clone().unwrap();
+                    let number = i.itunes_ext().unwrap().episode().unwrap().to_string();
+                    (title, number)
+                })
+                .collect::<Vec<_>>()
+        };
+        let three = numbers(3).await;
+        assert_eq!(
+            three,
+            [("Episode 3", "3"), ("Episode 2", "2"), ("Episode 1", "1")]
+                .map(|(t, n)| (t.to_string(), n.to_string()))
+        );
+        // A new episode takes the next number, leaving the others alone
+        let four = numbers(4).await;
+        assert_eq!(four[0], ("Episode 4".to_string(), "4".to_string()));
+        assert_eq!(four[1..], three[..]);
+    }
+
+    #[tokio::test]
+    async fn weekday_keeps_only_episodes_released_on_those_days() {
+        // Mon 1 Jan 2024 to Sun 14 Jan, newest first
+        let bbc = MockBbc::start(Upstream {
+            episodes: (1..=14).rev().map(testing::episode_json).collect(),
+            ..Upstream::default()
+        })
+        .await;
+        let state = testing::state(&bbc, &[]);
+        let feed = |query: &'static str| {
+            let state = state.clone();
+            async move { titles(&text(get(&state, &format!("/{}?{}", PROGRAMME, query)).await).await) }
+        };
+        assert_eq!(feed("weekday=fri").await, ["Episode 12", "Episode 5"]);
+        assert_eq!(
+            feed("weekday=mon,%20wed").await,
+            ["Episode 10", "Episode 8", "Episode 3", "Episode 1"]
+        );
+        assert_eq!(feed("weekday=sun&limit=1").await, ["Episode 14"]);
+        assert_eq!(feed("weekday=fri&since=2024-01-06").await, ["Episode 12"]);
+        assert_eq!(feed("").await.len(), 14);
+    }
+
+    #[tokio::test]
+    async fn no_cache_refetches_and_no_store_leaves_the_cache_alone() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--debug-headers"]);
+        let feed = format!("/{}", PROGRAMME);
+        let get_with = |cache_control: &'static str| {
+            let (state, feed) = (state.clone(), feed.clone());
+            async move {
+                let mut req = Request::get(feed);
+                if !cache_control.is_empty() {
+                    req = req.header(header::CACHE_CONTROL, cache_control);
+                }
+                let resp = testing::send(&state, req.body(Body::empty()).unwrap()).await;
+                testing::header(&resp, "x-cache").to_string()
+            }
+        };
+        let episode_pages = || bbc.hits("/programmes/playable");
+
+        assert_eq!(get_with("").await, "MISS");
+        assert_eq!(get_with("").await, "HIT");
+        assert_eq!(episode_pages(), 1);
+
+        assert_eq!(get_with("no-cache").await, "MISS");
+        assert_eq!(episode_pages(), 2);
+        assert_eq!(get_with("").await, "HIT");
+        assert_eq!(
+            get(&state, &format!("{}?nocache=true", feed))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(episode_pages(), 3);
+
+        assert_eq!(get_with("max-age=0, No-Store").await, "MISS");
+        assert_eq!(episode_pages(), 4);
+        // The feed cached before no-store is still there
+        assert_eq!(get_with("").await, "HIT");
+        assert_eq!(episode_pages(), 4);
+    }
+
+    #[tokio::test]
+    async fn no_store_results_are_not_cached() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--debug-headers"]);
+        let feed = format!("/{}", PROGRAMME);
+        let req = Request::get(&feed)
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            testing::header(&testing::send(&state, req).await, "x-cache"),
+            "MISS"
+        );
+        assert_eq!(
+            testing::header(&get(&state, &feed).await, "x-cache"),
+            "MISS"
+        );
+        assert_eq!(testing::header(&get(&state, &feed).await, "x-cache"), "HIT");
+        assert_eq!(bbc.hits("/programmes/playable"), 2);
+    }
+
+    #[tokio::test]
+    async fn total_duration_sums_the_episodes_in_the_feed() {
+        let bbc = MockBbc::start(Upstream {
+            episodes: (1..=12).rev().map(testing::episode_json).collect(),
+            ..Upstream::default()
+        })
+        .await;
+        let description = |args: &'static [&'static str], query: &'static str| {
+            let state = testing::state(&bbc, args);
+            async move {
+                let feed = text(get(&state, &format!("/{}?{}", PROGRAMME, query)).await).await;
+                rss::Channel::read_from(feed.as_bytes())
+                    .unwrap()
+                    .description
+            }
+        };
+        let args = &["--show-total-duration"];
+        // 1 to 12 minutes
+        assert_eq!(
+            description(args, "").await,
+            "Medium\n\nTotal duration: 01:18:00"
+        );
+        // Only the newest two, 12 and 11 minutes
+        assert_eq!(
+            description(args, "limit=2").await,
+            "Medium\n\nTotal duration: 00:23:00"
+        );
+        assert_eq!(description(&[], "").await, "Medium");
+    }
+
+    #[test]
+    fn feed_requests_split_into_a_decoded_id_and_options() {
+        let parse = |uri: &str| {
+            let uri: hyper::Uri = uri.parse().unwrap();
+            let path = parse_feed_path(uri.path()).unwrap();
+            FeedRequest::parse(path, uri.query(), &Overrides::new())
+        };
+        for uri in ["/p0000001", "/p0000001?", "/p0000001/"] {
+            let feed = parse(uri).unwrap();
+            assert_eq!(feed.id, PROGRAMME, "{}", uri);
+            assert_eq!(feed.opts.limit, None);
+        }
+        let feed = parse("/p%30000001?limit=2&weekday=fri").unwrap();
+        assert_eq!(feed.id, PROGRAMME);
+        assert_eq!(feed.opts.limit, Some(2));
+        assert_eq!(feed.opts.weekdays, Some(vec![chrono::Weekday::Fri]));
+
+        let feed = parse("/p0000001.ics/since/2024-01-05?limit=1").unwrap();
+        assert_eq!(feed.id, PROGRAMME);
+        assert_eq!(feed.opts.format, Format::Ics);
+        assert_eq!(feed.opts.since, chrono::NaiveDate::from_ymd_opt(2024, 1, 5));
+        assert_eq!(feed.opts.limit, Some(1));
+
+        assert!(matches!(parse("/p%FF?"), Err(ProxyError::BadRequest(_))));
+        assert!(matches!(
+            parse("/p0000001?limit=x"),
+            Err(ProxyError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn dc_creator_is_the_presenters_or_bbc() {
+        let mut upstream = Upstream::default();
+        upstream.episodes[0]["contributors"] = serde_json::json!([
+            { "name": "Ann Presenter", "role": "Presenter" },
+            { "name": "A Producer", "role": "Producer" },
+            { "name": "Bob Host", "role": "host" },
+        ]);
+        let bbc = MockBbc::start(upstream).await;
+        let feed = |args: &'static [&'static str]| {
+            let state = testing::state(&bbc, args);
+            async move { text(get(&state, &format!("/{}", PROGRAMME)).await).await }
+        };
+
+        let xml = feed(&["--dc-creator"]).await;
+        assert!(xml.contains(r#"xmlns:dc="http://purl.org/dc/elements/1.1/""#));
+        let channel = rss::Channel::read_from(xml.as_bytes()).unwrap();
+        let creators: Vec<Vec<String>> = channel
+            .items
+            .iter()
+            .map(|i| i.dublin_core_ext().unwrap().creators().to_vec())
+            .collect();
+        assert_eq!(creators, [["Ann Presenter, Bob Host"], ["BBC"], ["BBC"]]);
+
+        let xml = feed(&[]).await;
+        assert!(!xml.contains("xmlns:dc"));
+        assert!(!xml.contains("dc:creator"));
+    }
+
+    #[tokio::test]
+    async fn serial_mode_lists_numbered_parts_oldest_first() {
+        let bbc = MockBbc::start(Upstream {
+            episodes: (1..=4).rev().map(testing::episode_json).collect(),
+            ..Upstream::default()
+        })
+        .await;
+        let state = testing::state(&bbc, &[]);
+        let feed = |query: &'static str| {
+            let state = state.clone();
+            async move {
+                let xml = text(get(&state, &format!("/{}?{}", PROGRAMME, query)).await).await;
+                rss::Channel::read_from(xml.as_bytes()).unwrap()
+            }
+        };
+
+        let channel = feed("serial_mode=true").await;
+        assert_eq!(channel.itunes_ext().unwrap().r#type(), Some("serial"));
+        let parts: Vec<(&str, &str)> = channel
+            .items
+            .iter()
+            .map(|i| {
+                let number = i.itunes_ext().unwrap().episode().unwrap();
+                (i.title().unwrap(), number)
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ("Episode 1", "1"),
+                ("Episode 2", "2"),
+                ("Episode 3", "3"),
+                ("Episode 4", "4")
+            ]
+        );
+
+        let channel = feed("").await;
+        assert_eq!(channel.itunes_ext().unwrap().r#type(), None);
+        assert_eq!(channel.items[0].title(), Some("Episode 4"));
+        assert!(channel.items[0].itunes_ext().unwrap().episode().is_none());
+    }
+
+    #[test]
+    fn each_field_gets_the_synopsis_asked_for() {
+        let e = testing::episode(1);
+        let fields = |query| {
+            let item = item(&[], &e, query);
+            let itunes = item.itunes_ext().unwrap();
+            (
+                itunes.subtitle().map(String::from),
+                itunes.summary().map(String::from),
+                item.description().map(String::from),
+                item.content().map(String::from),
+            )
+        };
+        let some = |s: &str| Some(s.to_string());
+
+        let (subtitle, summary, description, content) = fields("");
+        assert_eq!((subtitle, summary), (some("Short 1"), None));
+        assert_eq!(description, some("Long 1"));
+        assert!(content.unwrap().contains("Long 1"));
+
+        let query = "subtitle_syn=long&summary_syn=medium&desc_syn=short&content_syn=medium";
+        let (subtitle, summary, description, content) = fields(query);
+        assert_eq!((subtitle, summary), (some("Long 1"), some("Medium 1")));
+        assert_eq!(description, some("Short 1"));
+        let content = content.unwrap();
+        assert!(content.contains("Medium 1") && !content.contains("Short"));
+
+        for key in ["subtitle_syn", "summary_syn", "desc_syn", "content_syn"] {
+            let query = format!("{}=longest", key);
+            let result = FeedOptions::from_query(Some(&query));
+            assert!(matches!(result, Err(ProxyError::BadRequest(_))), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_enclosures_leaves_everything_but_the_audio() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let feed = |query: &'static str| {
+            let state = state.clone();
+            async move { text(get(&state, &format!("/{}?{}", PROGRAMME, query)).await).await }
+        };
+        let headlines = feed("no_enclosures=true").await;
+        assert!(!headlines.contains("<enclosure"));
+        let full = feed("").await;
+        assert_eq!(full.matches("<enclosure").count(), 3);
+
+        let headlines = rss::Channel::read_from(headlines.as_bytes()).unwrap();
+        let full = rss::Channel::read_from(full.as_bytes()).unwrap();
+        assert_eq!(headlines.items.len(), 3);
+        for (headline, item) in headlines.items.iter().zip(&full.items) {
+            assert!(headline.enclosure.is_none());
+            assert_eq!(headline.title, item.title);
+            assert_eq!(headline.description, item.description);
+            assert_eq!(headline.pub_date, item.pub_date);
+            assert_eq!(headline.link, item.link);
+            assert_eq!(headline.guid, item.guid);
+            assert!(headline.link.is_some() && headline.pub_date.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn response_headers_are_on_feeds_and_errors() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let args = [
+            "--response-header",
+            "X-Content-Type-Options: nosniff",
+            "--response-header",
+            "strict-transport-security:max-age=31536000",
+        ];
+        let state = testing::state(&bbc, &args);
+        let feed = format!("/{}", PROGRAMME);
+        let bad_request = format!("/{}?limit=x", PROGRAMME);
+        for (path, status) in [
+            (feed.as_str(), StatusCode::OK),
+            ("/p0000404", StatusCode::NOT_FOUND),
+            (bad_request.as_str(), StatusCode::BAD_REQUEST),
+            ("/no/such/route", StatusCode::NOT_FOUND),
+        ] {
+            let response = get(&state, path).await;
+            assert_eq!(response.status(), status, "{}", path);
+            assert_eq!(
+                testing::header(&response, "x-content-type-options"),
+                "nosniff"
+            );
+            let hsts = testing::header(&response, "strict-transport-security");
+            assert_eq!(hsts, "max-age=31536000", "{}", path);
+        }
+    }
+
+    #[test]
+    fn episode_type_comes_from_the_title() {
+        let episode_type = |args: &[&str], title: &str| {
+            let mut e = testing::episode(1);
+            e.titles.secondary = title.to_string();
+            let item = item(args, &e, "");
+            item.itunes_ext().unwrap().episode_type().map(String::from)
+        };
+        let on = &["--episode-type"];
+        for (title, expected) in [
+            ("Episode 1", "full"),
+            ("Series 2 Trailer", "trailer"),
+            ("coming soon: Series 2", "trailer"),
+            ("A Bonus Episode", "bonus"),
+            ("Extra-time special", "bonus"),
+            // Whole words only
+            ("Trailers of the Century", "full"),
+            ("Extraordinary People", "full"),
+        ] {
+            assert_eq!(
+                episode_type(on, title).as_deref(),
+                Some(expected),
+                "{}",
+                title
+            );
+        }
+        assert_eq!(episode_type(&[], "Series 2 Trailer"), None);
+        let custom = &[
+            "--episode-type",
+            "--trailer-pattern",
+            "Teaser",
+            "--bonus-pattern",
+            "Q&A",
+        ];
+        assert_eq!(episode_type(custom, "Teaser").as_deref(), Some("trailer"));
+        assert_eq!(
+            episode_type(custom, "Listener Q&A").as_deref(),
+            Some("bonus")
+        );
+        assert_eq!(
+            episode_type(custom, "Series 2 Trailer").as_deref(),
+            Some("full")
+        );
+    }
+
+    #[test]
+    fn request_parameters_win_over_overrides() {
+        let overrides =
+            Overrides::from([(PROGRAMME.to_string(), "order=date_asc&limit=5".to_string())]);
+        let parse = |path, query| {
+            let path = parse_feed_path(path).unwrap();
+            FeedRequest::parse(path, query, &overrides).unwrap().opts
+        };
+        let opts = parse("/p0000001", None);
+        assert_eq!((opts.order, opts.limit), (Some(Order::DateAsc), Some(5)));
+        let opts = parse("/p0000001", Some("limit=2"));
+        assert_eq!((opts.order, opts.limit), (Some(Order::DateAsc), Some(2)));
+        // Other programmes are unaffected
+        let opts = parse("/p0000002", None);
+        assert_eq!((opts.order, opts.limit), (None, None));
+    }
+
+    #[tokio::test]
+    async fn enclosure_urls_are_absolute_or_relative() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let enclosure = |args: &'static [&'static str]| {
+            let mut argv = vec!["--proxy-audio"];
+            argv.extend_from_slice(args);
+            let state = testing::state(&bbc, &argv);
+            async move {
+                let req = Request::get(format!("/{}", PROGRAMME))
+                    .header(header::HOST, "proxy.example:8223")
+                    .body(Body::empty())
+                    .unwrap();
+                let feed = text(testing::send(&state, req).await).await;
+                let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+                channel.items[0].enclosure.clone().unwrap().url
+            }
+        };
+        let audio = "/audio?url=https%3A%2F%2Fopen.live.bbc.co.uk%2FHigh%2F1.mp3";
+        let base = &["--base-url", "https://pods.example/sounds/"];
+
+        assert_eq!(
+            enclosure(&[]).await,
+            format!("http://proxy.example:8223{}", audio)
+        );
+        assert_eq!(
+            enclosure(base).await,
+            format!("https://pods.example/sounds{}", audio)
+        );
+        let relative = &["--enclosure-url-mode", "relative"];
+        assert_eq!(enclosure(relative).await, audio);
+        let relative_under_base = &[
+            "--enclosure-url-mode",
+            "relative",
+            "--base-url",
+            "https://pods.example/sounds/",
+        ];
+        assert_eq!(
+            enclosure(relative_under_base).await,
+            format!("/sounds{}", audio)
+        );
+    }
+
+    #[tokio::test]
+    async fn feeds_can_be_fetched_in_ranges() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let feed = format!("/{}", PROGRAMME);
+        let whole = get(&state, &feed).await;
+        assert_eq!(testing::header(&whole, "accept-ranges"), "bytes");
+        let whole = testing::bytes(whole).await;
+
+        let ranged = |range: &'static str| {
+            let req = Request::get(&feed)
+                .header(header::RANGE, range)
+                .body(Body::empty())
+                .unwrap();
+            testing::send(&state, req)
+        };
+        let response = ranged("bytes=0-99").await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            testing::header(&response, "content-range"),
+            format!("bytes 0-99/{}", whole.len())
+        );
+        assert_eq!(testing::bytes(response).await, whole.slice(..100));
+
+        // Served from the cache this time
+        let response = ranged("bytes=-10").await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            testing::bytes(response).await,
+            whole.slice(whole.len() - 10..)
+        );
+        assert_eq!(bbc.hits("/playable"), 1);
+
+        let response = ranged("bytes=99999999-").await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            testing::header(&response, "content-range"),
+            format!("bytes */{}", whole.len())
+        );
+    }
+
+    #[test]
+    fn empty_synopses_fall_back_to_another_length() {
+        let only = |short: &str, medium: &str, long: &str| {
+            let mut e = testing::episode(1);
+            e.synopses = bbc::PodSynopses {
+                short: short.to_string(),
+                medium: medium.to_string(),
+                long: long.to_string(),
+            };
+            e
+        };
+        let fields = |args: &[&str], e: &PodEpisode| {
+            let item = item(args, e, "summary_syn=medium");
+            let itunes = item.itunes_ext().unwrap();
+            (
+                itunes.subtitle().map(String::from),
+                itunes.summary().map(String::from),
+                item.description().map(String::from),
+            )
+        };
+        let all = |s: &str| {
+            (
+                Some(s.to_string()),
+                Some(s.to_string()),
+                Some(s.to_string()),
+            )
+        };
+
+        assert_eq!(fields(&[], &only("", "", "L")), all("L"));
+        assert_eq!(fields(&[], &only("S", "", "")), all("S"));
+        assert_eq!(fields(&[], &only("", "M", "")), all("M"));
+        // The nearest length is preferred
+        let e = only("S", "", "L");
+        let (_, summary, _) = fields(&[], &e);
+        assert_eq!(summary.as_deref(), Some("S"));
+
+        let off = &["--synopsis-fallback", "off"];
+        let (subtitle, summary, description) = fields(off, &only("", "", "L"));
+        assert_eq!((subtitle, summary), (None, None));
+        assert_eq!(description.as_deref(), Some("L"));
+    }
+
+    #[tokio::test]
+    async fn empty_itunes_fields_are_left_out() {
+        let mut upstream = Upstream {
+            episodes: vec![testing::episode_json(1)],
+            ..Upstream::default()
+        };
+        upstream.container["image_url"] = "".into();
+        upstream.episodes[0]["image_url"] = "".into();
+        upstream.episodes[0]["synopses"]["short"] = "".into();
+        let bbc = MockBbc::start(upstream).await;
+        let args = [
+            "--synopsis-fallback",
+            "off",
+            "--managing-editor",
+            "",
+            "--webmaster",
+            "",
+        ];
+        let state = testing::state(&bbc, &args);
+        let query = "summary_syn=short";
+        let feed = text(get(&state, &format!("/{}?{}", PROGRAMME, query)).await).await;
+        for element in [
+            "<itunes:image",
+            "<itunes:subtitle",
+            "<itunes:summary",
+            "<itunes:owner",
+        ] {
+            assert!(!feed.contains(element), "{} in {}", element, feed);
+        }
+        let empty = regex::Regex::new(r"<([\w:]+)[^>]*></([\w:]+)>").unwrap();
+        let empty = empty.captures_iter(&feed).find(|c| c[1] == c[2]);
+        assert!(empty.is_none(), "{:?} in {}", empty, feed);
+        // Fields with values are still there
+        assert!(feed.contains("<itunes:author>BBC</itunes:author>"));
+        assert!(feed.contains("<itunes:duration>00:01:00</itunes:duration>"));
+    }
+
+    #[tokio::test]
+    async fn filter_regex_matches_the_chosen_field() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let feed = |query: &'static str| {
+            let state = state.clone();
+            async move { titles(&text(get(&state, &format!("/{}?{}", PROGRAMME, query)).await).await) }
+        };
+        assert_eq!(
+            feed("filter_regex=%5EEPISODE%20%5B13%5D%24").await,
+            ["Episode 1", "Episode 3"]
+        );
+        // Titles don't mention "long", descriptions do
+        assert!(feed("filter_regex=long").await.is_empty());
+        assert_eq!(
+            feed("filter_regex=long%202&filter_field=description").await,
+            ["Episode 2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn links_use_the_request_host_only_when_allowed() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let args = [
+            "--proxy-audio",
+            "--base-url",
+            "https://pods.example/sounds",
+            "--allowed-host",
+            "alt.example:8443",
+        ];
+        let state = testing::state(&bbc, &args);
+        let links = |host: &'static str| {
+            let state = state.clone();
+            async move {
+                let get = |uri: String| {
+                    let req = Request::get(uri)
+                        .header(header::HOST, host)
+                        .body(Body::empty())
+                        .unwrap();
+                    testing::send(&state, req)
+                };
+                let feed = text(get(format!("/{}", PROGRAMME)).await).await;
+                let feed = rss::Channel::read_from(feed.as_bytes()).unwrap();
+                let timeline = text(get(format!("/timeline?ids={}", PROGRAMME)).await).await;
+                let timeline = rss::Channel::read_from(timeline.as_bytes()).unwrap();
+                (feed.items[0].enclosure.clone().unwrap().url, timeline.link)
+            }
+        };
+        let audio = "/sounds/audio?url=https%3A%2F%2Fopen.live.bbc.co.uk%2FHigh%2F1.mp3";
+        let timeline = "/sounds/timeline?ids=p0000001";
+        for host in ["alt.example:8443", "ALT.example:8443"] {
+            let (enclosure, link) = links(host).await;
+            assert_eq!(
+                enclosure,
+                format!("https://alt.example:8443{}", audio),
+                "{}",
+                host
+            );
+            assert_eq!(
+                link,
+                format!("https://alt.example:8443{}", timeline),
+                "{}",
+                host
+            );
+        }
+        // Any other host, including the allowed one on another port, gets
+        // --base-url, though the cached feed was built for the allowed host
+        for host in ["evil.example", "alt.example:80"] {
+            let (enclosure, link) = links(host).await;
+            assert_eq!(
+                enclosure,
+                format!("https://pods.example{}", audio),
+                "{}",
+                host
+            );
+            assert_eq!(
+                link,
+                format!("https://pods.example{}", timeline),
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_trailing_slash_is_the_same_feed() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--debug-headers"]);
+        for (plain, slashed) in [
+            ("/p0000001", "/p0000001/"),
+            ("/p0000001/since/2024-01-02", "/p0000001/since/2024-01-02/"),
+            ("/p0000001.ics?limit=1", "/p0000001.ics/?limit=1"),
+        ] {
+            let plain = get(&state, plain).await;
+            assert_eq!(plain.status(), StatusCode::OK);
+            let slashed = get(&state, slashed).await;
+            assert_eq!(slashed.status(), StatusCode::OK);
+            // One cache entry serves both
+            assert_eq!(testing::header(&slashed, "x-cache"), "HIT");
+            assert_eq!(testing::bytes(plain).await, testing::bytes(slashed).await);
+        }
+        let feed = text(get(&state, "/p0000001/since/2024-01-02/").await).await;
+        assert_eq!(titles(&feed), ["Episode 2", "Episode 3"]);
+        for path in ["/p0000001//", "/p0000001/since/"] {
+            let status = get(&state, path).await.status();
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[test]
+    fn desc_format_applies_to_description_and_content() {
+        let mut e = testing::episode(1);
+        e.synopses.long = "<p>With <b>Jane</b></p><p>Part 2</p>".to_string();
+        let fields = |query| {
+            let item = item(&[], &e, query);
+            (item.description.unwrap(), item.content.unwrap())
+        };
+        // As BBC gives it by default
+        assert_eq!(fields("").0, e.synopses.long);
+        assert_eq!(
+            fields("desc_format=text"),
+            (
+                "With Jane\n\nPart 2".to_string(),
+                "With Jane\n\nPart 2".to_string()
+            )
+        );
+        assert_eq!(
+            fields("desc_format=markdown"),
+            (
+                "With **Jane**\n\nPart 2".to_string(),
+                "With **Jane**\n\nPart 2".to_string()
+            )
+        );
+        let result = FeedOptions::from_query(Some("desc_format=rtf"));
+        assert!(matches!(result, Err(ProxyError::BadRequest(_))));
+    }
+
+    #[test]
+    fn show_quality_names_the_chosen_variant() {
+        let title = |e: &PodEpisode, query| item(&[], e, query).title.unwrap();
+        let e = testing::episode(1);
+        assert_eq!(title(&e, "show_quality=true"), "Episode 1 (High)");
+        assert_eq!(
+            title(&e, "show_quality=true&max_size=2"),
+            "Episode 1 (Medium)"
+        );
+        assert_eq!(title(&e, ""), "Episode 1");
+        // Nothing to name without an enclosure or a label
+        assert_eq!(
+            title(&e, "show_quality=true&no_enclosures=true"),
+            "Episode 1"
+        );
+        let mut unlabelled = e.clone();
+        unlabelled.download.quality_variants.high.label = String::new();
+        assert_eq!(title(&unlabelled, "show_quality=true"), "Episode 1");
+    }
+
+    #[tokio::test]
+    async fn browsers_get_a_page_with_a_retry_link_when_bbc_fails() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let request = |path: &str, accept: &str| {
+            let req = Request::get(path)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap();
+            testing::send(&state, req)
+        };
+        let browser = "text/html,application/xhtml+xml;q=0.9,*/*;q=0.8";
+        let path = "/p0000001?limit=2&title=%22x%22";
+        bbc.set_failing(true);
+
+        let response = request(path, browser).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            testing::header(&response, "content-type"),
+            "text/html; charset=utf-8"
+        );
+        let page = text(response).await;
+        assert!(
+            page.contains(r#"<a href="/p0000001?limit=2&amp;title=%22x%22">Try again</a>"#),
+            "{}",
+            page
+        );
+        assert!(page.contains("503 Service Unavailable"), "{}", page);
+
+        // Podcast clients get the plain error
+        let response = request(path, "application/rss+xml, */*").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(!text(response).await.contains("<html>"));
+        // As do errors that trying again won't fix
+        bbc.set_failing(false);
+        let response = request("/p0000404", browser).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!text(response).await.contains("<html>"));
+    }
+
+    #[tokio::test]
+    async fn renders_abandoned_at_shutdown_still_reach_the_fallback_dir() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let dir = testing::tempdir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = testing::state(&bbc, &["--fallback-feed-dir", dir.to_str().unwrap()]);
+        // The client goes away as soon as the render has started
+        let path = format!("/{}", PROGRAMME);
+        let mut request = Box::pin(get(&state, &path));
+        assert!(futures::poll!(&mut request).is_pending());
+        drop(request);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        assert_eq!(state.feeds.settle().await, 1);
+        let saved: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].extension().unwrap(), "xml");
+        let feed = std::fs::read_to_string(&saved[0]).unwrap();
+        assert_eq!(titles(&feed).len(), 3);
+        // Nothing is left to wait for
+        assert_eq!(state.feeds.settle().await, 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pub_dates_can_be_normalised_to_utc() {
+        let pub_date = |args: &[&str], released: &str| {
+            let mut e = testing::episode(1);
+            e.release.date = released.to_string();
+            item(args, &e, "").pub_date.unwrap()
+        };
+        let utc = &["--normalize-dates-utc"];
+        for (released, original, normalised) in [
+            (
+                "2024-06-01T10:00:00+01:00",
+                "Sat, 01 Jun 2024 10:00:00 +0100",
+                "Sat, 01 Jun 2024 09:00:00 +0000",
+            ),
+            (
+                "2024-06-01T22:30:00-05:00",
+                "Sat, 01 Jun 2024 22:30:00 -0500",
+                "Sun, 02 Jun 2024 03:30:00 +0000",
+            ),
+            (
+                "2024-06-01T09:00:00Z",
+                "Sat, 01 Jun 2024 09:00:00 +0000",
+                "Sat, 01 Jun 2024 09:00:00 +0000",
+            ),
+        ] {
+            assert_eq!(pub_date(&[], released), original);
+            assert_eq!(pub_date(utc, released), normalised);
+        }
+    }
+
+    #[test]
+    fn big_feeds_lose_their_oldest_episodes_to_fit() {
+        // Released in no particular order
+        let data: Vec<PodEpisode> = (1..=20)
+            .map(|n| testing::episode((n * 7) % 20 + 1))
+            .collect();
+        let episodes = PodEpisodes {
+            total: data.len(),
+            data,
+            partial: false,
+        };
+        let rss = |args: &[&str]| {
+            let mut argv = vec!["soundsproxy"];
+            argv.extend_from_slice(args);
+            let config = Config::try_parse_from(argv).unwrap();
+            let opts = FeedOptions::default();
+            build_rss(
+                PROGRAMME,
+                &testing::container(),
+                &episodes,
+                &config,
+                &opts,
+                None,
+            )
+        };
+        let full = rss(&[]);
+        assert_eq!(
+            rss(&["--max-response-bytes", &full.len().to_string()]),
+            full
+        );
+
+        let max = full.len() / 2;
+        let shortened = rss(&["--max-response-bytes", &max.to_string()]);
+        assert!(shortened.len() <= max);
+        let channel = rss::Channel::read_from(shortened.as_bytes()).unwrap();
+        let kept = channel.items.len();
+        assert!(kept > 0 && kept < 20, "{}", kept);
+        let mut numbers: Vec<usize> = titles(&shortened)
+            .iter()
+            .map(|t| t["Episode ".len()..].parse().unwrap())
+            .collect();
+        numbers.sort_unstable();
+        assert_eq!(numbers, (21 - kept..=20).collect::<Vec<_>>());
+        assert!(channel
+            .description
+            .ends_with(&format!("shortened to its newest {} of 20 episodes.", kept)));
+
+        // Too small for any episode
+        let bare = rss(&["--max-response-bytes", "10"]);
+        assert!(rss::Channel::read_from(bare.as_bytes())
+            .unwrap()
+            .items
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn container_pid_is_in_the_channel_when_asked() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let feed = |args: &'static [&'static str], path: &'static str| {
+            let state = testing::state(&bbc, args);
+            async move { text(get(&state, path).await).await }
+        };
+        let on = &["--container-pid"];
+        let xml = feed(on, "/p0000001").await;
+        assert!(xml.contains(r#"xmlns:bbc="https://github.com/wilt00/soundsproxy#bbc""#));
+        let channel = rss::Channel::read_from(xml.as_bytes()).unwrap();
+        let pid = &channel.extensions["bbc"]["containerPid"][0];
+        assert_eq!(pid.value(), Some(PROGRAMME));
+        // Timelines have no one programme, and it's off by default
+        let timeline = feed(on, "/timeline?ids=p0000001").await;
+        assert!(!timeline.contains("containerPid"));
+        let xml = feed(&[], "/p0000001").await;
+        assert!(!xml.contains("containerPid") && !xml.contains("xmlns:bbc"));
+    }
+
+    #[test]
+    fn weak_etags_ignore_only_the_ttl() {
+        let feed = |ttl, title| {
+            format!(
+                "<rss><channel><title>{}</title><ttl>{}</ttl><item/></channel></rss>",
+                title, ttl
+            )
+        };
+        let strong = |body: &str| etag(body, EtagMode::Strong);
+        let weak = |body: &str| etag(body, EtagMode::Weak);
+        assert!(strong(&feed(60, "A")).starts_with('"'));
+        assert_ne!(strong(&feed(60, "A")), strong(&feed(30, "A")));
+        assert!(weak(&feed(60, "A")).starts_with("W/\""));
+        assert_eq!(weak(&feed(60, "A")), weak(&feed(30, "A")));
+        assert_ne!(weak(&feed(60, "A")), weak(&feed(60, "B")));
+    }
+
+    #[tokio::test]
+    async fn matching_if_none_match_is_not_modified() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let path = format!("/{}", PROGRAMME);
+        let conditional = |tags: &str, gzip: bool| {
+            let mut req = Request::get(&path).header(header::IF_NONE_MATCH, tags);
+            if gzip {
+                req = req.header(header::ACCEPT_ENCODING, "gzip");
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let state = testing::state(&bbc, &[]);
+        let response = get(&state, &path).await;
+        let tag = testing::header(&response, "etag").to_string();
+        assert!(tag.starts_with('"'), "{}", tag);
+        let weakened = format!("W/{}", tag);
+        let listed = format!("\"other\", {}", tag);
+        for tags in [tag.as_str(), &weakened, &listed, "*"] {
+            let response = testing::send(&state, conditional(tags, false)).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", tags);
+            assert_eq!(testing::header(&response, "etag"), tag);
+            assert!(testing::bytes(response).await.is_empty());
+        }
+        let response = testing::send(&state, conditional("\"other\"", false)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(testing::header(&response, "etag"), tag);
+
+        // The gzipped body's ETag differs from the plain one's
+        let state = testing::state(&bbc, &["--compress-cache"]);
+        let response = testing::send(&state, conditional(&tag, true)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let gzipped = testing::header(&response, "etag").to_string();
+        assert_eq!(gzipped, format!("{}-gzip\"", tag.trim_end_matches('"')));
+        let response = testing::send(&state, conditional(&gzipped, true)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(testing::header(&response, "vary"), "Accept-Encoding");
+        let response = testing::send(&state, conditional(&tag, false)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let state = testing::state(&bbc, &["--etag-mode", "weak"]);
+        let response = get(&state, &path).await;
+        assert!(testing::header(&response, "etag").starts_with("W/\""));
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_keeps_requests_from_bbc() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let feed = format!("/{}", PROGRAMME);
+        let state = testing::state(
+            &bbc,
+            &["--maintenance", "--maintenance-message", "Back soon"],
+        );
+        let info = format!("/info/{}.json", PROGRAMME);
+        let timeline = format!("/timeline?ids={}", PROGRAMME);
+        for path in [feed.as_str(), &info, &timeline, "/audio?vpid=p0000001"] {
+            let response = get(&state, path).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "{}",
+                path
+            );
+            assert_eq!(text(response).await, "Back soon");
+        }
+        assert_eq!(text(get(&state, "/health").await).await, "OK");
+        assert_eq!(bbc.hits(""), 0);
+
+        // Switched off, as by SIGHUP, feeds are fetched again
+        state.maintenance.store(false, Ordering::Relaxed);
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_can_serve_cached_feeds() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let feed = format!("/{}", PROGRAMME);
+        for (serve_cached, status) in [
+            (false, StatusCode::SERVICE_UNAVAILABLE),
+            (true, StatusCode::OK),
+        ] {
+            let args: &[&str] = if serve_cached {
+                &["--maintenance-serve-cached"]
+            } else {
+                &[]
+            };
+            let state = testing::state(&bbc, args);
+            assert_eq!(get(&state, &feed).await.status(), StatusCode::OK);
+            let hits = bbc.hits("");
+            state.maintenance.store(true, Ordering::Relaxed);
+            assert_eq!(
+                get(&state, &feed).await.status(),
+                status,
+                "{}",
+                serve_cached
+            );
+            // Feeds that aren't cached can't be served either way
+            let uncached = get(&state, &format!("{}?order=duration_asc", feed)).await;
+            assert_eq!(uncached.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(bbc.hits(""), hits);
+        }
+    }
+
+    #[tokio::test]
+    async fn content_encoded_and_its_namespace_can_be_left_out() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let namespace = r#"xmlns:content="http://purl.org/rss/1.0/modules/content/""#;
+        let feed = |args: &'static [&'static str]| {
+            let state = testing::state(&bbc, args);
+            async move { text(get(&state, "/p0000001").await).await }
+        };
+        let xml = feed(&[]).await;
+        assert!(xml.contains(namespace) && xml.contains("<content:encoded>"));
+        let xml = feed(&["--no-content-encoded"]).await;
+        assert!(!xml.contains("content:encoded") && !xml.contains("xmlns:content"));
+        let channel = rss::Channel::read_from(xml.as_bytes()).unwrap();
+        assert!(channel.items.iter().all(|i| i.description.is_some()));
+    }
+
+    #[tokio::test]
+    async fn channel_docs_and_contacts_come_from_config() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let read = |args: &'static [&'static str]| {
+            let state = testing::state(&bbc, args);
+            async move {
+                let xml = text(get(&state, "/p0000001").await).await;
+                rss::Channel::read_from(xml.as_bytes()).unwrap()
+            }
+        };
+        let channel = read(&[]).await;
+        assert_eq!(
+            channel.docs(),
+            Some("https://www.rssboard.org/rss-specification")
+        );
+        assert_eq!(
+            (channel.webmaster(), channel.managing_editor()),
+            (None, None)
+        );
+
+        let args = &[
+            "--docs",
+            "https://example.com/rss",
+            "--webmaster",
+            "web@example.com",
+            "--managing-editor",
+            "editor@example.com",
+        ];
+        let channel = read(args).await;
+        assert_eq!(channel.docs(), Some("https://example.com/rss"));
+        assert_eq!(channel.webmaster(), Some("web@example.com"));
+        assert_eq!(channel.managing_editor(), Some("editor@example.com"));
+        let owner = channel.itunes_ext().unwrap().owner().unwrap();
+        assert_eq!(owner.email(), Some("editor@example.com"));
+    }
+
+    #[tokio::test]
+    async fn since_path_filters_like_the_query() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let path = text(get(&state, "/p0000001/since/2024-01-02").await).await;
+        let query = text(get(&state, "/p0000001?since=2024-01-02").await).await;
+        assert_eq!(titles(&path), ["Episode 2", "Episode 3"]);
+        assert_eq!(titles(&path), titles(&query));
+        let hits = bbc.hits("");
+        for date in ["2024-13-01", "2024-1-2x", "yesterday", "%FF"] {
+            let response = get(&state, &format!("/p0000001/since/{}", date)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", date);
+        }
+        assert_eq!(bbc.hits(""), hits);
+    }
+
+    #[tokio::test]
+    async fn feed_requests_count_as_in_flight_until_they_finish() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let path = format!("/{}", PROGRAMME);
+        let mut first = Box::pin(get(&state, &path));
+        let mut second = Box::pin(get(&state, &path));
+        assert!(futures::poll!(&mut first).is_pending());
+        assert!(futures::poll!(&mut second).is_pending());
+        assert_eq!(state.metrics.in_flight(), 2);
+        let metrics = text(get(&state, "/metrics").await).await;
+        assert!(
+            metrics.contains("soundsproxy_in_flight_requests 2"),
+            "{}",
+            metrics
+        );
+
+        // Finished or abandoned, they're no longer counted
+        assert_eq!(first.await.status(), StatusCode::OK);
+        assert_eq!(state.metrics.in_flight(), 1);
+        drop(second);
+        assert_eq!(state.metrics.in_flight(), 0);
+        assert_eq!(
+            get(&state, "/p0000404").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(state.metrics.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn presenters_become_podcast_hosts_when_asked() {
+        let mut upstream = Upstream::default();
+        let contributors = serde_json::json!([
+            { "name": "Ann Presenter", "role": "Presenter" },
+            { "name": "A Producer", "role": "Producer" },
+            { "name": "Bob Host", "role": "host" },
+        ]);
+        upstream.container["contributors"] = contributors.clone();
+        upstream.episodes[0]["contributors"] = contributors;
+        let bbc = MockBbc::start(upstream).await;
+        let feed = |args: &'static [&'static str]| {
+            let state = testing::state(&bbc, args);
+            async move { text(get(&state, &format!("/{}", PROGRAMME)).await).await }
+        };
+        let hosts = |extensions: &rss::extension::ExtensionMap| -> Vec<String> {
+            let people = extensions.get("podcast").and_then(|p| p.get("person"));
+            people
+                .into_iter()
+                .flatten()
+                .map(|person| {
+                    assert_eq!(person.attrs()["role"], "host");
+                    person.value().unwrap().to_string()
+                })
+                .collect()
+        };
+
+        let xml = feed(&["--podcast-person"]).await;
+        assert!(xml.contains(r#"xmlns:podcast="https://podcastindex.org/namespace/1.0""#));
+        let channel = rss::Channel::read_from(xml.as_bytes()).unwrap();
+        assert_eq!(hosts(&channel.extensions), ["Ann Presenter", "Bob Host"]);
+        let items: Vec<_> = channel.items.iter().map(|i| hosts(&i.extensions)).collect();
+        // Episodes without contributors get no elements
+        assert_eq!(items, [vec!["Ann Presenter", "Bob Host"], vec![], vec![]]);
+
+        let xml = feed(&[]).await;
+        assert!(!xml.contains("podcast:person"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_feed_requests_are_counted_as_coalesced() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let path = format!("/{}", PROGRAMME);
+        let requests = (0..10).map(|_| get(&state, &path));
+        let responses = futures::future::join_all(requests).await;
+        assert!(responses.iter().all(|r| r.status() == StatusCode::OK));
+        assert_eq!(bbc.hits("/container"), 1);
+        let count = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+        assert_eq!(count(&state.metrics.cache_misses), 1);
+        assert_eq!(count(&state.metrics.cache_coalesced), 9);
+        assert_eq!(get(&state, &path).await.status(), StatusCode::OK);
+        assert_eq!(count(&state.metrics.cache_hits), 1);
+        let metrics = text(get(&state, "/metrics").await).await;
+        assert!(
+            metrics.contains("soundsproxy_cache_coalesced_total 9"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn enclosure_length_is_the_chosen_variants_size() {
+        let e = testing::episode(1);
+        for (query, label, size) in [
+            ("", "High", "3000000"),
+            ("max_size=2.5", "Medium", "2000000"),
+            ("max_size=1", "Low", "1000000"),
+            ("max_size=0.5", "Low", "1000000"),
+        ] {
+            let enclosure = item(&[], &e, query).enclosure.unwrap();
+            assert_eq!(enclosure.length, size, "{}", query);
+            assert!(enclosure.url.contains(&format!("/{}/", label)), "{}", query);
+        }
+    }
+
+    #[test]
+    fn enclosure_length_can_be_estimated_from_the_duration() {
+        // 10 minutes at 128 and 48 kbit/s
+        let e = testing::episode(10);
+        let args = &["--estimate-length-from-duration"];
+        assert_eq!(item(args, &e, "").enclosure.unwrap().length, "9600000");
+        assert_eq!(
+            item(args, &e, "max_size=1").enclosure.unwrap().length,
+            "3600000"
+        );
+        // BBC's figure otherwise
+        assert_eq!(item(&[], &e, "").enclosure.unwrap().length, "3000000");
+    }
+
+    #[test]
+    fn ttl_shortens_before_the_first_episode_expires() {
+        let config = Config::try_parse_from(["soundsproxy", "--feed-ttl", "120"]).unwrap();
+        let now: DateTime<Utc> = "2024-01-01T00:00:00Z".parse().unwrap();
+        let ttl = |until: &[Option<&str>]| {
+            let episodes = PodEpisodes {
+                data: until
+                    .iter()
+                    .map(|until| episode("2023-12-01T00:00:00Z", *until))
+                    .collect(),
+                total: until.len(),
+                partial: false,
+            };
+            feed_ttl(&episodes, &config, now)
+        };
+        // Half of the 90 minutes left of the soonest to expire
+        let soon = [
+            Some("2024-01-01T03:00:00Z"),
+            Some("2024-01-01T01:30:00Z"),
+            None,
+        ];
+        assert_eq!(ttl(&soon), 45);
+        // Never more than --feed-ttl, nor less than a minute
+        assert_eq!(ttl(&[Some("2024-02-01T00:00:00Z")]), 120);
+        assert_eq!(ttl(&[Some("2024-01-01T00:00:30Z")]), 1);
+        // Episodes already gone, or without an end, don't count
+        assert_eq!(ttl(&[Some("2023-12-31T00:00:00Z"), None]), 120);
+        assert_eq!(ttl(&[]), 120);
+    }
+
+    #[test]
+    fn min_days_left_past_the_end_of_time() {
+        let e = episode("2024-01-01T00:00:00Z", Some("2030-01-01T00:00:00Z"));
+        let undated = episode("2024-01-01T00:00:00Z", None);
+        let opts = FeedOptions::from_query(Some("min_days_left=36500")).unwrap();
+        let now = chrono::MAX_DATETIME - chrono::Duration::days(1);
+        assert!(!matches_filters(&e, &opts, now));
+        assert!(matches_filters(&undated, &opts, now));
+    }
+
+    #[test]
+    fn min_days_left() {
+        let e = episode("2024-01-01T00:00:00Z", Some("2024-01-10T00:00:00Z"));
+        let now = "2024-01-05T00:00:00Z".parse().unwrap();
+        let opts =
+            |days| FeedOptions::from_query(Some(&format!("min_days_left={}", days))).unwrap();
+        assert!(matches_filters(&e, &opts(4), now));
+        assert!(!matches_filters(&e, &opts(6), now));
+    }
+
+    #[tokio::test]
+    async fn health_answers_when_client_is_at_its_limit() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--max-concurrent-per-ip", "1"]);
+        let slot = state.concurrency.acquire([127, 0, 0, 1].into(), 1).unwrap();
+        assert_eq!(get(&state, "/health").await.status(), StatusCode::OK);
+        assert_eq!(get(&state, "/metrics").await.status(), StatusCode::OK);
+        let feed = format!("/{}", PROGRAMME);
+        let response = get(&state, &feed).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        drop(slot);
+        let response = get(&state, &feed).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // The slot was released once the body was complete
+        assert!(text(response).await.contains("<rss"));
+        assert!(state
+            .concurrency
+            .acquire([127, 0, 0, 1].into(), 1)
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn metrics_addr_moves_metrics_and_health_off_the_public_router() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--metrics-addr", "127.0.0.1:9100"]);
+        let ops = |path: &str| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            ops_router(req, state.clone())
+        };
+        for path in ["/metrics", "/health"] {
+            assert_eq!(get(&state, path).await.status(), StatusCode::NOT_FOUND);
+            assert_eq!(ops(path).await.unwrap().status(), StatusCode::OK);
+        }
+        let feed = format!("/{}", PROGRAMME);
+        assert_eq!(ops(&feed).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // Both listeners share one state
+        assert_eq!(get(&state, &feed).await.status(), StatusCode::OK);
+        let metrics = text(ops("/metrics").await.unwrap()).await;
+        assert!(
+            metrics.contains("soundsproxy_feed_requests_total 1"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[test]
+    fn concurrency_is_unlimited_by_default() {
+        let config = Config::try_parse_from(["soundsproxy"]).unwrap();
+        assert_eq!(config.max_concurrent_per_ip, 0);
+    }
+
+    #[tokio::test]
+    async fn streamed_body_holds_its_slot() {
+        let limit = Arc::new(ConcurrencyLimit::default());
+        let ip = [127, 0, 0, 1].into();
+        let (mut tx, body) = Body::channel();
+        let response = hold_until_sent(Response::new(body), limit.acquire(ip, 1).unwrap());
+        assert!(limit.acquire(ip, 1).is_none());
+        tx.send_data(Bytes::from_static(b"audio")).await.unwrap();
+        drop(tx);
+        assert_eq!(&testing::bytes(response).await[..], b"audio");
+        assert!(limit.acquire(ip, 1).is_some());
+
+        // Complete bodies don't need to hold on to it
+        let response = hold_until_sent(
+            Response::new(Body::from("feed")),
+            limit.acquire(ip, 1).unwrap(),
+        );
+        assert!(limit.acquire(ip, 1).is_some());
+        drop(response);
+    }
+
+    #[tokio::test]
+    async fn malformed_feed_ids_are_rejected_before_bbc() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let long = format!("/{}", "a".repeat(5000));
+        for path in [
+            long.as_str(),
+            "/..%2F..%2Fx",
+            "/%2e%2e%2fx",
+            "/p0000001%2F..",
+            "/p0000001;x",
+        ] {
+            let response = get(&state, path).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        // Paths with real slashes aren't feed paths at all
+        assert_eq!(
+            get(&state, "/../p0000001").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(bbc.hits(""), 0);
+    }
+
+    #[tokio::test]
+    async fn options_is_answered_only_for_routes_that_exist() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let options = |state: Arc<AppState>, path: &'static str| async move {
+            let req = Request::options(path).body(Body::empty()).unwrap();
+            testing::send(&state, req).await.status()
+        };
+        let state = testing::state(&bbc, &[]);
+        for path in [
+            "/",
+            "/audio",
+            "/timeline",
+            "/info/p0000001.json",
+            "/p0000001",
+            "/health",
+        ] {
+            assert_eq!(
+                options(state.clone(), path).await,
+                StatusCode::NO_CONTENT,
+                "{}",
+                path
+            );
+        }
+        for path in ["/nope/x", "/info/p0000001", "/p0000001/since"] {
+            assert_eq!(
+                options(state.clone(), path).await,
+                StatusCode::NOT_FOUND,
+                "{}",
+                path
+            );
+        }
+        // Served on their own port instead
+        let state = testing::state(&bbc, &["--metrics-addr", "127.0.0.1:0"]);
+        assert_eq!(options(state, "/health").await, StatusCode::NOT_FOUND);
+        assert_eq!(bbc.hits(""), 0);
+    }
+
+    #[tokio::test]
+    async fn fallback_feeds_are_kept_per_set_of_options() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let dir = testing::tempdir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let state = testing::state(&bbc, &["--fallback-feed-dir", dir.to_str().unwrap()]);
+        let path = format!("/{}", PROGRAMME);
+        let feed = text(get(&state, &path).await).await;
+        get(&state, &format!("{}?utm_source=a", path)).await;
+        get(&state, &format!("{}?utm_source=b&nocache=true", path)).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        get(&state, &format!("{}?limit=1", path)).await;
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        // Not in the in-memory cache, but the same feed as the first
+        bbc.set_failing(true);
+        let response = get(&state, &format!("{}?utm_source=c", path)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(text(response).await, feed);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn oldest_fallback_feeds_are_removed_beyond_the_limit() {
+        let dir = testing::tempdir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let start = std::time::SystemTime::now();
+        for (n, name) in ["a.xml", "b.ics", "c.csv", "d.tmp"].iter().enumerate() {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_len(10).unwrap();
+            file.set_modified(start + std::time::Duration::from_secs(n as u64))
+                .unwrap();
+        }
+        prune_fallbacks(&dir, 25).await.unwrap();
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["b.ics", "c.csv", "d.tmp"]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn listener_is_bound_before_hyper_takes_it() {
+        let listener = bind_listener(SocketAddr::from(([127, 0, 0, 1], 0)), 1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Non-blocking, as `Server::from_tcp` needs
+        let err = listener.accept().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(listener.accept().is_ok());
+        let err = bind_listener(addr, 1).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        let config = |args: &[&str]| {
+            let mut argv = vec!["soundsproxy"];
+            argv.extend_from_slice(args);
+            Config::try_parse_from(argv).unwrap()
+        };
+        let defaults = config(&[]);
+        assert_eq!(
+            (defaults.listen_backlog, defaults.tcp_nodelay),
+            (1024, true)
+        );
+        let tuned = config(&["--listen-backlog", "4096", "--tcp-nodelay", "false"]);
+        assert_eq!((tuned.listen_backlog, tuned.tcp_nodelay), (4096, false));
+    }
+
+    /// Serve `state` on a local port, as `main` does, returning its address
+    fn serve(state: Arc<AppState>) -> SocketAddr {
+        let listener = bind_listener(SocketAddr::from(([127, 0, 0, 1], 0)), 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let http2 = state.config.http2;
+        let svc = make_service_fn(move |conn: &AddrStream| {
+            let state = state.clone();
+            let remote = conn.remote_addr();
+            async move {
+                Ok::<_, GenericError>(service_fn(move |req| router(req, state.clone(), remote)))
+            }
+        });
+        tokio::spawn(server(listener, true, http2).serve(svc));
+        addr
+    }
+
+    #[tokio::test]
+    async fn feeds_are_served_over_both_http_versions() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let addr = serve(testing::state(&bbc, &[]));
+        let url = format!("http://{}/{}", addr, PROGRAMME);
+        let h1 = reqwest::Client::new().get(&url).send().await.unwrap();
+        assert_eq!(h1.version(), reqwest::Version::HTTP_11);
+        let h1 = h1.text().await.unwrap();
+        let h2 = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap()
+            .get(&url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(h2.version(), reqwest::Version::HTTP_2);
+        assert_eq!(h2.text().await.unwrap(), h1);
+    }
+
+    #[tokio::test]
+    async fn http2_can_be_turned_off() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let addr = serve(testing::state(&bbc, &["--http2", "false"]));
+        let h2 = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap()
+            .get(format!("http://{}/health", addr))
+            .send()
+            .await;
+        assert!(h2.is_err());
+    }
+
+    /// Send `request` over a raw connection to `addr`, returning the status
+    /// line of the response
+    async fn raw_status(addr: SocketAddr, request: &str) -> String {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        let mut conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let mut line = String::new();
+        BufReader::new(conn).read_line(&mut line).await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_answered_without_waiting_for_a_body() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let addr = serve(testing::state(&bbc, &[]));
+        // The promised body is never sent
+        let request = format!(
+            "GET /{} HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n",
+            PROGRAMME
+        );
+        let status = tokio::time::timeout(Duration::from_secs(5), raw_status(addr, &request))
+            .await
+            .expect("no response while the body was outstanding");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let request = "GET / HTTP/1.1\r\nHost: x\r\nExpect: something-else\r\n\r\n";
+        assert_eq!(
+            raw_status(addr, request).await,
+            "HTTP/1.1 417 Expectation Failed"
+        );
+    }
+
+    #[tokio::test]
+    async fn pretty_feeds_are_indented() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let compact = text(get(&state, &format!("/{}", PROGRAMME)).await).await;
+        let pretty = text(get(&state, &format!("/{}?pretty=true", PROGRAMME)).await).await;
+        assert!(!compact.contains("\n"));
+        assert!(pretty.contains("\n  <channel>\n    <title>"), "{}", pretty);
+        let parse = |feed: &str| rss::Channel::read_from(feed.as_bytes()).unwrap();
+        assert_eq!(parse(&compact), parse(&pretty));
+    }
+
+    #[tokio::test]
+    async fn mime_overrides_every_enclosure() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let feed = text(get(&state, &format!("/{}?mime=audio/mp4", PROGRAMME)).await).await;
+        let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+        assert_eq!(channel.items.len(), 3);
+        for item in &channel.items {
+            assert_eq!(item.enclosure.as_ref().unwrap().mime_type, "audio/mp4");
+        }
+        let response = get(&state, &format!("/{}?mime=text/html", PROGRAMME)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn debug_headers_say_how_the_feed_was_served() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let args = [
+            "--debug-headers",
+            "--cache-ttl",
+            "1",
+            "--serve-stale",
+            "3600",
+        ];
+        let state = testing::state(&bbc, &args);
+        let path = format!("/{}", PROGRAMME);
+        let response = get(&state, &path).await;
+        assert_eq!(testing::header(&response, "x-cache"), "MISS");
+        assert_eq!(testing::header(&response, "x-cache-age"), "0");
+        let response = get(&state, &path).await;
+        assert_eq!(testing::header(&response, "x-cache"), "HIT");
+        // Off by default
+        let response = get(&testing::state(&bbc, &[]), &path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(testing::header(&response, "x-cache"), "");
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        bbc.set_failing(true);
+        let response = get(&state, &path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(testing::header(&response, "x-cache"), "STALE");
+        assert_eq!(testing::header(&response, "x-cache-age"), "1");
+    }
+
+    #[tokio::test]
+    async fn channel_and_items_get_their_own_image_sizes() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let args = [
+            "--channel-image-size",
+            "1400x1400",
+            "--item-image-size",
+            "160x160",
+        ];
+        let feed = text(get(&testing::state(&bbc, &args), &format!("/{}", PROGRAMME)).await).await;
+        let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+        let image = |itunes: Option<&str>| itunes.unwrap_or_default().to_string();
+        assert_eq!(
+            image(channel.itunes_ext().unwrap().image()),
+            "https://ichef.bbci.co.uk/images/ic/1400x1400/p.jpg"
+        );
+        for item in &channel.items {
+            assert_eq!(
+                image(item.itunes_ext().unwrap().image()),
+                "https://ichef.bbci.co.uk/images/ic/160x160/e.jpg"
+            );
+        }
+        let feed = text(get(&testing::state(&bbc, &[]), &format!("/{}", PROGRAMME)).await).await;
+        assert_eq!(
+            feed.matches("/288x288/").count(),
+            feed.matches("/images/ic/").count()
+        );
+    }
+
+    #[tokio::test]
+    async fn funding_is_emitted_only_when_configured() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let path = format!("/{}", PROGRAMME);
+        let args = [
+            "--funding-url",
+            "https://example.com/give?a=1&b=2",
+            "--funding-text",
+            "Support us",
+        ];
+        let feed = text(get(&testing::state(&bbc, &args), &path).await).await;
+        let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+        let funding = &channel.extensions["podcast"]["funding"][0];
+        assert_eq!(funding.value.as_deref(), Some("Support us"));
+        assert_eq!(funding.attrs["url"], "https://example.com/give?a=1&b=2");
+        let feed = text(get(&testing::state(&bbc, &[]), &path).await).await;
+        assert!(!feed.contains("podcast:funding"));
+        let url_only = ["soundsproxy", "--funding-url", "https://example.com"];
+        assert!(Config::try_parse_from(url_only).is_err());
+    }
+
+    #[tokio::test]
+    async fn programme_without_episodes_can_be_an_empty_feed() {
+        let mut upstream = Upstream::default();
+        let body = testing::not_found(PROGRAMME).to_string();
+        upstream.raw.insert(
+            "/v2/programmes/playable".to_string(),
+            testing::Raw::new(200, "application/json", &body),
+        );
+        let bbc = MockBbc::start(upstream).await;
+        let path = format!("/{}", PROGRAMME);
+        let response = get(&testing::state(&bbc, &[]), &path).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let state = testing::state(&bbc, &["--empty-on-no-episodes"]);
+        let response = get(&state, &path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let channel = rss::Channel::read_from(text(response).await.as_bytes()).unwrap();
+        assert_eq!(channel.title, "Programme");
+        assert!(channel.items.is_empty());
+        // A programme that doesn't exist at all is still not found
+        let response = get(&state, "/p0000002").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn compressed_cache_serves_identical_feeds() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let pinned = ["--deterministic-time", "2024-06-01T00:00:00Z"];
+        let path = format!("/{}", PROGRAMME);
+        let plain = testing::bytes(get(&testing::state(&bbc, &pinned), &path).await).await;
+        let state = testing::state(&bbc, &[&pinned[..], &["--compress-cache"]].concat());
+        // Rendered, then from the cache
+        for _ in 0..2 {
+            let response = get(&state, &path).await;
+            assert_eq!(testing::header(&response, "content-encoding"), "");
+            assert_eq!(testing::bytes(response).await, plain);
+        }
+        let req = Request::get(path.as_str())
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = testing::send(&state, req).await;
+        assert_eq!(testing::header(&response, "content-encoding"), "gzip");
+        assert_eq!(gunzip(&testing::bytes(response).await), plain);
+    }
+
+    #[tokio::test]
+    async fn image_size_must_be_an_allowed_recipe() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let feed = text(get(&state, &format!("/{}?image_size=1400x1400", PROGRAMME)).await).await;
+        assert!(feed.contains("/images/ic/1400x1400/p.jpg"));
+        assert!(feed.contains("/images/ic/1400x1400/e.jpg"));
+        assert!(!feed.contains("288x288"));
+        let response = get(&state, &format!("/{}?image_size=123x45", PROGRAMME)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The operator's list replaces the built-in one
+        let state = testing::state(&bbc, &["--image-recipe", "123x45,288x288"]);
+        let response = get(&state, &format!("/{}?image_size=123x45", PROGRAMME)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get(&state, &format!("/{}?image_size=1400x1400", PROGRAMME)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn configured_image_sizes_must_be_allowed_recipes() {
+        let config = |args: &[&str]| {
+            let mut argv = vec!["soundsproxy"];
+            argv.extend_from_slice(args);
+            Config::try_parse_from(argv).unwrap()
+        };
+        assert!(config(&[]).check_image_recipes().is_ok());
+        assert!(config(&["--item-image-size", "123x45"])
+            .check_image_recipes()
+            .is_err());
+        assert!(config(&[
+            "--item-image-size",
+            "123x45",
+            "--image-recipe",
+            "123x45,288x288"
+        ])
+        .check_image_recipes()
+        .is_ok());
+    }
 
-    if let Err(e) = graceful.await {
-        eprintln!("server error: {}", e);
+    #[tokio::test]
+    async fn pinned_time_renders_identical_feeds() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let render = |time: &'static str, query: &'static str| {
+            let state = testing::state(&bbc, &["--deterministic-time", time]);
+            async move {
+                let path = format!("/{}?{}", PROGRAMME, query);
+                let first = testing::bytes(get(&state, &path).await).await;
+                let path = format!("{}&nocache=true", path);
+                let second = testing::bytes(get(&state, &path).await).await;
+                assert_eq!(first, second);
+                String::from_utf8(first.to_vec()).unwrap()
+            }
+        };
+        let feed = render("2024-06-01T00:00:00Z", "").await;
+        assert!(feed.contains("<ttl>60</ttl>"));
+        assert_eq!(render("2024-06-01T00:00:00Z", "").await, feed);
+        // An hour before the episodes expire, the ttl is halved to 30 minutes
+        let feed = render("2029-12-31T23:00:00Z", "").await;
+        assert!(feed.contains("<ttl>30</ttl>"));
+        assert_eq!(feed.matches("<item>").count(), 3);
+        // and min_days_left=1 leaves them out
+        let feed = render("2029-12-31T23:00:00Z", "min_days_left=1").await;
+        assert_eq!(feed.matches("<item>").count(), 0);
+        let feed = render("2029-12-30T23:00:00Z", "min_days_left=1").await;
+        assert_eq!(feed.matches("<item>").count(), 3);
     }
 }
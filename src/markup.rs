@@ -0,0 +1,202 @@
+/// Escape text for use in HTML, including in attribute values
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// How `convert` renders the tags it understands
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Text,
+    Markdown,
+}
+
+/// Strip any HTML from `html`, leaving plain text with paragraphs separated
+/// by blank lines
+pub fn to_text(html: &str) -> String {
+    convert(html, Style::Text)
+}
+
+/// Convert the simple HTML that can turn up in synopses to Markdown:
+/// paragraphs, line breaks, lists, bold, italics and links. Other tags are
+/// dropped.
+pub fn to_markdown(html: &str) -> String {
+    convert(html, Style::Markdown)
+}
+
+fn convert(html: &str, style: Style) -> String {
+    let markdown = style == Style::Markdown;
+    let mut out = String::new();
+    // Targets of the open `<a>` tags, written out when they close
+    let mut links: Vec<Option<String>> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&decode_entities(&rest[..start]));
+        rest = &rest[start..];
+        // A `<` not followed by a tag name, or never closed, is just text
+        let is_tag = rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/');
+        let end = match rest.find('>') {
+            Some(end) if is_tag => end,
+            _ => {
+                out.push('<');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match (name.as_str(), closing) {
+            ("br", _) => out.push('\n'),
+            ("p" | "div" | "ul" | "ol", _) => out.push_str("\n\n"),
+            ("li", false) if markdown => out.push_str("\n- "),
+            ("li", false) => out.push('\n'),
+            ("b" | "strong", _) if markdown => out.push_str("**"),
+            ("i" | "em", _) if markdown => out.push('*'),
+            ("a", false) => {
+                let href = attribute(tag, "href");
+                if markdown && href.is_some() {
+                    out.push('[');
+                }
+                links.push(href);
+            }
+            ("a", true) => {
+                if let Some(Some(href)) = links.pop() {
+                    if markdown {
+                        out.push_str(&format!("]({})", href));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out.push_str(&decode_entities(rest));
+    tidy(&out)
+}
+
+/// The value of attribute `name` in the inside of a tag, if it has one
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let at = tag.to_ascii_lowercase().find(&format!("{}=", name))? + name.len() + 1;
+    let value = &tag[at..];
+    let value = match value.chars().next()? {
+        quote @ ('"' | '\'') => value[1..].split(quote).next()?,
+        _ => value.split(char::is_whitespace).next()?,
+    };
+    Some(decode_entities(value))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest
+            .find(';')
+            .and_then(|end| Some((entity(&rest[1..end])?, end)));
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn entity(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Trim each line and collapse runs of blank lines into one
+fn tidy(text: &str) -> String {
+    let mut out = String::new();
+    let mut blank = false;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            blank = true;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank { "\n\n" } else { "\n" });
+        }
+        blank = false;
+        out.push_str(line);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYNOPSIS: &str = "<p>With <b>Jane</b> &amp; <em>guests</em>.</p>\
+        <ul><li>One</li><li>Two</li></ul>\
+        <p>More at <a href=\"https://bbc.co.uk/x?a=1&amp;b=2\">the site</a>.<br>Thanks</p>";
+
+    #[test]
+    fn html_becomes_plain_text() {
+        assert_eq!(
+            to_text(SYNOPSIS),
+            "With Jane & guests.\n\nOne\nTwo\n\nMore at the site.\nThanks"
+        );
+    }
+
+    #[test]
+    fn html_becomes_markdown() {
+        assert_eq!(
+            to_markdown(SYNOPSIS),
+            "With **Jane** & *guests*.\n\n- One\n- Two\n\n\
+             More at [the site](https://bbc.co.uk/x?a=1&b=2).\nThanks"
+        );
+        // Links without a target are just their text
+        assert_eq!(to_markdown("<a name='x'>here</a>"), "here");
+        assert_eq!(to_markdown("<A HREF='/y'>there</A>"), "[there](/y)");
+    }
+
+    #[test]
+    fn text_that_only_looks_like_markup_is_kept() {
+        assert_eq!(to_text("1 < 2 and 3 > 2"), "1 < 2 and 3 > 2");
+        assert_eq!(to_text("a <b unclosed"), "a <b unclosed");
+        assert_eq!(
+            to_text("&copy; &#169; &#xA9; &bogus; & co"),
+            "&copy; © © &bogus; & co"
+        );
+        assert_eq!(to_text("Plain"), "Plain");
+    }
+
+    #[test]
+    fn escaping_covers_attributes() {
+        assert_eq!(
+            escape(r#"<a href="x">&</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters exposed in Prometheus text format on `/metrics`
+#[derive(Default)]
+pub struct Metrics {
+    pub feed_requests: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    /// Requests that waited on another request's in-flight upstream fetch
+    pub cache_coalesced: AtomicU64,
+    /// Requests served an expired feed because BBC couldn't be reached
+    pub cache_stale: AtomicU64,
+    /// Feed requests currently being handled
+    in_flight: AtomicU64,
+}
+
+/// Counts a feed request as in flight until dropped
+pub struct InFlight<'a>(&'a AtomicU64);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn track_in_flight(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(&self.in_flight)
+    }
+
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "soundsproxy_feed_requests_total",
+                "Feed requests received",
+                &self.feed_requests,
+            ),
+            (
+                "soundsproxy_cache_hits_total",
+                "Feed requests served from cache",
+                &self.cache_hits,
+            ),
+            (
+                "soundsproxy_cache_misses_total",
+                "Feed requests that fetched from BBC",
+                &self.cache_misses,
+            ),
+            (
+                "soundsproxy_cache_coalesced_total",
+                "Feed requests that waited on another request's fetch",
+                &self.cache_coalesced,
+            ),
+            (
+                "soundsproxy_cache_stale_total",
+                "Feed requests served an expired feed after a failed fetch",
+                &self.cache_stale,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(
+            out,
+            "# HELP soundsproxy_in_flight_requests Feed requests currently being handled"
+        );
+        let _ = writeln!(out, "# TYPE soundsproxy_in_flight_requests gauge");
+        let _ = writeln!(out, "soundsproxy_in_flight_requests {}", self.in_flight());
+        out
+    }
+}
@@ -0,0 +1,457 @@
+use chrono::{NaiveDate, Weekday};
+use regex::{Regex, RegexBuilder};
+use url::form_urlencoded;
+
+use crate::bbc::PodSynopses;
+use crate::cache::CachePolicy;
+use crate::error::ProxyError;
+
+/// Output format of a feed, chosen by the path's extension
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Rss,
+    /// iCalendar, from `/{id}.ics`
+    Ics,
+    /// One row per episode, from `/{id}.csv`
+    Csv,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Rss => "application/rss+xml",
+            Format::Ics => "text/calendar; charset=utf-8",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+/// Episode order, from `?order=`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    DateAsc,
+    DateDesc,
+    DurationAsc,
+    DurationDesc,
+}
+
+impl Order {
+    fn parse(value: &str) -> Result<Self, ProxyError> {
+        match value {
+            "date_asc" => Ok(Order::DateAsc),
+            "date_desc" => Ok(Order::DateDesc),
+            "duration_asc" => Ok(Order::DurationAsc),
+            "duration_desc" => Ok(Order::DurationDesc),
+            _ => Err(ProxyError::BadRequest(format!(
+                "Invalid order {:?}, expected date_asc, date_desc, duration_asc or duration_desc",
+                value
+            ))),
+        }
+    }
+}
+
+/// The episode field `?filter_regex=` is matched against, from
+/// `?filter_field=`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterField {
+    #[default]
+    Title,
+    /// The long synopsis
+    Description,
+}
+
+impl FilterField {
+    fn parse(value: &str) -> Result<Self, ProxyError> {
+        match value {
+            "title" => Ok(FilterField::Title),
+            "description" => Ok(FilterField::Description),
+            _ => Err(ProxyError::BadRequest(format!(
+                "Invalid filter_field {:?}, expected title or description",
+                value
+            ))),
+        }
+    }
+}
+
+/// How item descriptions are written, from `?desc_format=`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DescFormat {
+    /// Descriptions as BBC gives them, with `content:encoded` as HTML
+    #[default]
+    Html,
+    /// Any HTML stripped from both
+    Text,
+    /// Simple HTML converted to Markdown in both
+    Markdown,
+}
+
+impl DescFormat {
+    fn parse(value: &str) -> Result<Self, ProxyError> {
+        match value {
+            "html" => Ok(DescFormat::Html),
+            "text" => Ok(DescFormat::Text),
+            "markdown" => Ok(DescFormat::Markdown),
+            _ => Err(ProxyError::BadRequest(format!(
+                "Invalid desc_format {:?}, expected html, text or markdown",
+                value
+            ))),
+        }
+    }
+}
+
+/// One of the three synopses BBC gives each episode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SynopsisLength {
+    Short,
+    Medium,
+    Long,
+}
+
+impl SynopsisLength {
+    fn parse(key: &str, value: &str) -> Result<Self, ProxyError> {
+        match value {
+            "short" => Ok(SynopsisLength::Short),
+            "medium" => Ok(SynopsisLength::Medium),
+            "long" => Ok(SynopsisLength::Long),
+            _ => Err(ProxyError::BadRequest(format!(
+                "Invalid {} {:?}, expected short, medium or long",
+                key, value
+            ))),
+        }
+    }
+
+    pub fn of(self, synopses: &PodSynopses) -> &str {
+        match self {
+            SynopsisLength::Short => &synopses.short,
+            SynopsisLength::Medium => &synopses.medium,
+            SynopsisLength::Long => &synopses.long,
+        }
+    }
+
+    /// This synopsis, or if it's empty the nearest length that isn't
+    pub fn of_or_nearest(self, synopses: &PodSynopses) -> &str {
+        let (short, medium, long) = (
+            SynopsisLength::Short,
+            SynopsisLength::Medium,
+            SynopsisLength::Long,
+        );
+        let order = match self {
+            SynopsisLength::Short => [short, medium, long],
+            SynopsisLength::Medium => [medium, short, long],
+            SynopsisLength::Long => [long, medium, short],
+        };
+        order
+            .into_iter()
+            .map(|length| length.of(synopses))
+            .find(|text| !text.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+/// Which synopsis fills each item field, from `?subtitle_syn=`,
+/// `?summary_syn=`, `?desc_syn=` and `?content_syn=`
+#[derive(Clone, Debug)]
+pub struct SynopsisFields {
+    pub subtitle: SynopsisLength,
+    /// `itunes:summary`, which is left out unless chosen
+    pub summary: Option<SynopsisLength>,
+    pub description: SynopsisLength,
+    /// `content:encoded`, which follows the description unless chosen
+    pub content: Option<SynopsisLength>,
+}
+
+impl Default for SynopsisFields {
+    fn default() -> Self {
+        SynopsisFields {
+            subtitle: SynopsisLength::Short,
+            summary: None,
+            description: SynopsisLength::Long,
+            content: None,
+        }
+    }
+}
+
+/// Per-request feed options, taken from the query string and path
+#[derive(Clone, Debug, Default)]
+pub struct FeedOptions {
+    pub format: Format,
+    /// Only include episodes released on or after this date
+    pub since: Option<NaiveDate>,
+    /// Largest enclosure to pick, in bytes, from `?max_size=` in MB
+    pub max_size: Option<u64>,
+    /// Leave out episodes that stop being available within this many days
+    pub min_days_left: Option<u32>,
+    /// Only include episodes released on these days of the week
+    pub weekdays: Option<Vec<Weekday>>,
+    /// Sort episodes, rather than keeping BBC's order
+    pub order: Option<Order>,
+    /// Only include the first this many episodes, after sorting
+    pub limit: Option<usize>,
+    /// Channel copyright line, overriding `--copyright`
+    pub copyright: Option<String>,
+    /// Start item descriptions with BBC's human-readable release date
+    pub show_release_label: bool,
+    /// Whether cached feeds and BBC responses may be used and updated, from
+    /// `?nocache=` or the `Cache-Control` header
+    pub cache: CachePolicy,
+    /// Indent the XML output
+    pub pretty: bool,
+    /// Enclosure MIME type override, from `?mime=`
+    pub mime: Option<&'static str>,
+    /// Present the feed as a serial: oldest first, marked `itunes:type`
+    /// serial and with episode numbers, from `?serial_mode=`
+    pub serial_mode: bool,
+    pub synopses: SynopsisFields,
+    /// Leave out enclosures, for a headlines-only feed
+    pub no_enclosures: bool,
+    /// Only include episodes whose `filter_field` matches this
+    pub filter_regex: Option<Regex>,
+    pub filter_field: FilterField,
+    pub desc_format: DescFormat,
+    /// BBC image recipe for the channel's and items' artwork, in place of
+    /// `--channel-image-size` and `--item-image-size`
+    pub image_size: Option<String>,
+    /// Append the enclosure's quality label to item titles
+    pub show_quality: bool,
+}
+
+/// Largest `?min_days_left=` accepted, about a century
+const MAX_DAYS_LEFT: u32 = 36_500;
+
+/// Longest `?filter_regex=` accepted, in bytes
+const MAX_REGEX_LEN: usize = 256;
+
+/// Most memory a `?filter_regex=` may compile to, in bytes
+const REGEX_SIZE_LIMIT: usize = 256 * 1024;
+
+/// MIME types `?mime=` may set on enclosures
+const AUDIO_MIME_TYPES: [&str; 9] = [
+    "audio/mpeg",
+    "audio/mp3",
+    "audio/mp4",
+    "audio/x-m4a",
+    "audio/aac",
+    "audio/ogg",
+    "audio/opus",
+    "audio/wav",
+    "audio/flac",
+];
+
+pub fn parse_date(value: &str) -> Result<NaiveDate, ProxyError> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        ProxyError::BadRequest(format!("Invalid date {:?}, expected YYYY-MM-DD", value))
+    })
+}
+
+pub fn parse_bool(key: &str, value: &str) -> Result<bool, ProxyError> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(ProxyError::BadRequest(format!(
+            "Invalid {} {:?}, expected true or false",
+            key, value
+        ))),
+    }
+}
+
+fn parse_size_mb(value: &str) -> Result<u64, ProxyError> {
+    match value.parse::<f64>() {
+        Ok(mb) if mb.is_finite() && mb > 0.0 => Ok((mb * 1_000_000.0) as u64),
+        _ => Err(ProxyError::BadRequest(format!(
+            "Invalid max_size {:?}, expected a positive number of MB",
+            value
+        ))),
+    }
+}
+
+fn parse_days(value: &str) -> Result<u32, ProxyError> {
+    match value.parse::<u32>() {
+        Ok(days) if days <= MAX_DAYS_LEFT => Ok(days),
+        _ => Err(ProxyError::BadRequest(format!(
+            "Invalid min_days_left {:?}, expected a whole number of days up to {}",
+            value, MAX_DAYS_LEFT
+        ))),
+    }
+}
+
+fn parse_weekdays(value: &str) -> Result<Vec<Weekday>, ProxyError> {
+    value
+        .split(',')
+        .map(|day| {
+            day.trim().parse::<Weekday>().map_err(|_| {
+                ProxyError::BadRequest(format!(
+                    "Invalid weekday {:?}, expected mon, tue, wed, thu, fri, sat or sun",
+                    day
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Compile a `?filter_regex=`, refusing patterns too long or too complex to
+/// compile within `REGEX_SIZE_LIMIT`. Matching is case-insensitive.
+fn parse_regex(value: &str) -> Result<Regex, ProxyError> {
+    if value.len() > MAX_REGEX_LEN {
+        return Err(ProxyError::BadRequest(format!(
+            "filter_regex may be at most {} bytes",
+            MAX_REGEX_LEN
+        )));
+    }
+    RegexBuilder::new(value)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|e| match e {
+            regex::Error::CompiledTooBig(_) => {
+                ProxyError::BadRequest("filter_regex is too complex".to_string())
+            }
+            e => ProxyError::BadRequest(format!("Invalid filter_regex {:?}: {}", value, e)),
+        })
+}
+
+fn parse_count(key: &str, value: &str) -> Result<usize, ProxyError> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(ProxyError::BadRequest(format!(
+            "Invalid {} {:?}, expected a positive integer",
+            key, value
+        ))),
+    }
+}
+
+impl FeedOptions {
+    /// Refuse an `?image_size=` that isn't one of the allowed `recipes`
+    pub fn check_image_size(&self, recipes: &[String]) -> Result<(), ProxyError> {
+        match &self.image_size {
+            Some(size) if !recipes.contains(size) => Err(ProxyError::BadRequest(format!(
+                "Unsupported image_size {:?}, expected one of {}",
+                size,
+                recipes.join(", ")
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn from_query(query: Option<&str>) -> Result<Self, ProxyError> {
+        let mut opts = FeedOptions::default();
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "since" => opts.since = Some(parse_date(&value)?),
+                "max_size" => opts.max_size = Some(parse_size_mb(&value)?),
+                "min_days_left" => opts.min_days_left = Some(parse_days(&value)?),
+                "weekday" => opts.weekdays = Some(parse_weekdays(&value)?),
+                "order" => opts.order = Some(Order::parse(&value)?),
+                "limit" => opts.limit = Some(parse_count(&key, &value)?),
+                "copyright" => opts.copyright = Some(value.into_owned()),
+                "show_release_label" => opts.show_release_label = parse_bool(&key, &value)?,
+                "nocache" if parse_bool(&key, &value)? => opts.cache = CachePolicy::Refresh,
+                "pretty" => opts.pretty = parse_bool(&key, &value)?,
+                "serial_mode" => opts.serial_mode = parse_bool(&key, &value)?,
+                "show_quality" => opts.show_quality = parse_bool(&key, &value)?,
+                "no_enclosures" => opts.no_enclosures = parse_bool(&key, &value)?,
+                "subtitle_syn" => opts.synopses.subtitle = SynopsisLength::parse(&key, &value)?,
+                "summary_syn" => opts.synopses.summary = Some(SynopsisLength::parse(&key, &value)?),
+                "desc_syn" => opts.synopses.description = SynopsisLength::parse(&key, &value)?,
+                "content_syn" => opts.synopses.content = Some(SynopsisLength::parse(&key, &value)?),
+                "filter_regex" => opts.filter_regex = Some(parse_regex(&value)?),
+                "filter_field" => opts.filter_field = FilterField::parse(&value)?,
+                "desc_format" => opts.desc_format = DescFormat::parse(&value)?,
+                "image_size" => opts.image_size = Some(value.into_owned()),
+                "mime" => {
+                    let mime = AUDIO_MIME_TYPES
+                        .iter()
+                        .find(|m| **m == value)
+                        .ok_or_else(|| {
+                            ProxyError::BadRequest(format!("Unsupported mime {:?}", value))
+                        })?;
+                    opts.mime = Some(mime);
+                }
+                _ => {}
+            }
+        }
+        // An explicit `?order=` still wins
+        if opts.serial_mode && opts.order.is_none() {
+            opts.order = Some(Order::DateAsc);
+        }
+        Ok(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(query: &str) -> Result<FeedOptions, ProxyError> {
+        FeedOptions::from_query(Some(query))
+    }
+
+    #[test]
+    fn min_days_left_is_bounded() {
+        let opts = query("min_days_left=36500").unwrap();
+        assert_eq!(opts.min_days_left, Some(MAX_DAYS_LEFT));
+        assert!(matches!(
+            query("min_days_left=36501"),
+            Err(ProxyError::BadRequest(_))
+        ));
+        assert!(matches!(
+            query("min_days_left=100000000"),
+            Err(ProxyError::BadRequest(_))
+        ));
+        assert!(matches!(
+            query("min_days_left=-1"),
+            Err(ProxyError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn weekdays_are_a_comma_separated_list() {
+        assert_eq!(
+            query("weekday=mon,Fri,%20sat").unwrap().weekdays,
+            Some(vec![Weekday::Mon, Weekday::Fri, Weekday::Sat])
+        );
+        assert_eq!(query("").unwrap().weekdays, None);
+        for bad in ["", "fri,", "funday", "mon;tue"] {
+            let result = query(&format!("weekday={}", bad));
+            assert!(matches!(result, Err(ProxyError::BadRequest(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn filter_regex_is_bounded() {
+        let regex = query("filter_regex=%5Eepisode%20%5B12%5D%24")
+            .unwrap()
+            .filter_regex;
+        assert!(regex.unwrap().is_match("Episode 2"));
+        let error = |value: &str| match query(&format!("filter_regex={}", value)) {
+            Err(ProxyError::BadRequest(message)) => message,
+            other => panic!("{:?} for {}", other.map(|o| o.filter_regex), value),
+        };
+        assert_eq!(
+            error(&"a".repeat(MAX_REGEX_LEN + 1)),
+            "filter_regex may be at most 256 bytes"
+        );
+        assert!(query(&format!("filter_regex={}", "a".repeat(MAX_REGEX_LEN))).is_ok());
+        assert_eq!(
+            error("%28%5Cw%7B100%7D%29%7B100%7D"),
+            "filter_regex is too complex"
+        );
+        assert!(error("%28unclosed").starts_with("Invalid filter_regex \"(unclosed\""));
+        assert!(query("filter_field=title").is_ok());
+        assert!(matches!(
+            query("filter_field=body"),
+            Err(ProxyError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn max_size_is_a_positive_number_of_megabytes() {
+        assert_eq!(query("max_size=2.5").unwrap().max_size, Some(2_500_000));
+        assert_eq!(query("max_size=40").unwrap().max_size, Some(40_000_000));
+        for bad in ["0", "-1", "abc", "inf", "NaN", ""] {
+            let result = query(&format!("max_size={}", bad));
+            assert!(matches!(result, Err(ProxyError::BadRequest(_))), "{}", bad);
+        }
+    }
+}
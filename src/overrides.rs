@@ -0,0 +1,86 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use url::form_urlencoded;
+
+use crate::options::FeedOptions;
+
+/// Default query parameters for particular programmes, as query strings keyed
+/// by programme ID, from `--overrides`
+pub type Overrides = HashMap<String, String>;
+
+/// Load a JSON file mapping programme IDs to objects of feed query parameters,
+/// such as `{"p02nq0gn": {"order": "date_asc", "max_size": 50}}`, checking
+/// that each programme's parameters are valid
+pub fn load(path: &Path) -> Result<Overrides, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let raw: HashMap<String, serde_json::Map<String, Value>> =
+        serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    raw.into_iter()
+        .map(|(id, params)| {
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            for (key, value) in params {
+                let value = match value {
+                    Value::String(s) => s,
+                    Value::Number(n) => n.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    other => {
+                        return Err(format!(
+                            "{}: {} must be a string, number or boolean, not {}",
+                            id, key, other
+                        ))
+                    }
+                };
+                query.append_pair(&key, &value);
+            }
+            let query = query.finish();
+            FeedOptions::from_query(Some(&query)).map_err(|e| format!("{}: {}", id, e))?;
+            Ok((id, query))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn load_json(json: &str) -> Result<Overrides, String> {
+        let dir = testing::tempdir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("overrides.json");
+        std::fs::write(&path, json).unwrap();
+        let result = load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn overrides_become_query_strings() {
+        let overrides = load_json(
+            r#"{
+                "p02nq0gn": {"order": "date_asc", "max_size": 50, "pretty": true},
+                "p0000001": {"copyright": "© BBC & friends"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(
+            overrides["p02nq0gn"],
+            "max_size=50&order=date_asc&pretty=true"
+        );
+        let opts = FeedOptions::from_query(Some(&overrides["p0000001"])).unwrap();
+        assert_eq!(opts.copyright.as_deref(), Some("© BBC & friends"));
+    }
+
+    #[test]
+    fn bad_overrides_are_refused_with_the_programme() {
+        let error = load_json(r#"{"p0000001": {"order": "sideways"}}"#).unwrap_err();
+        assert!(error.starts_with("p0000001: "), "{}", error);
+        let error = load_json(r#"{"p0000001": {"limit": [1, 2]}}"#).unwrap_err();
+        assert!(error.starts_with("p0000001: limit must be"), "{}", error);
+        assert!(load_json(r#"{"p0000001": "order=date_asc"}"#).is_err());
+        assert!(load_json("not json").is_err());
+        assert!(load(Path::new("/nonexistent/overrides.json")).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use hyper::header::HeaderValue;
+
+/// The inclusive byte range a `Range` header asks for within `len` bytes.
+/// `Ok(None)` means the whole body, as for a missing, malformed or
+/// multi-range header, which servers may ignore; `Err` means the range can't
+/// be satisfied.
+pub fn parse_range(value: Option<&HeaderValue>, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match value
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes="))
+    {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if len == 0 || range.0 >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+        parse_range(Some(&HeaderValue::from_str(value).unwrap()), len)
+    }
+
+    #[test]
+    fn ranges_within_the_body() {
+        assert_eq!(range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(range("bytes= 10-10", 1000), Ok(Some((10, 10))));
+        assert_eq!(range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // Ends past the body are clamped to it
+        assert_eq!(range("bytes=500-5000", 1000), Ok(Some((500, 999))));
+        assert_eq!(range("bytes=-5000", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn ranges_that_are_ignored_or_refused() {
+        assert_eq!(parse_range(None, 1000), Ok(None));
+        for ignored in [
+            "bytes=0-1,5-6",
+            "items=0-9",
+            "bytes=9-0",
+            "bytes=-0",
+            "bytes=x-9",
+            "bytes=5",
+        ] {
+            assert_eq!(range(ignored, 1000), Ok(None), "{}", ignored);
+        }
+        assert_eq!(range("bytes=1000-", 1000), Err(()));
+        assert_eq!(range("bytes=1000-2000", 1000), Err(()));
+        assert_eq!(range("bytes=0-0", 0), Err(()));
+    }
+}
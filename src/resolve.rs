@@ -0,0 +1,121 @@
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Serialize;
+use url::{form_urlencoded, Url};
+
+use crate::error::ProxyError;
+use crate::{base_url, AppState};
+
+#[derive(Serialize)]
+struct Resolved {
+    id: String,
+    feed_url: String,
+}
+
+/// BBC PIDs are short lowercase alphanumeric strings containing at least one
+/// digit, which tells them apart from path words like `programmes`
+fn is_pid(segment: &str) -> bool {
+    (8..=15).contains(&segment.len())
+        && segment.bytes().any(|b| b.is_ascii_digit())
+        && segment
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+}
+
+/// Extract the programme ID from a BBC Sounds or programmes URL such as
+/// `https://www.bbc.co.uk/sounds/play/b006qykl`
+fn extract_pid(raw: &str) -> Result<String, ProxyError> {
+    let url =
+        Url::parse(raw).map_err(|_| ProxyError::BadRequest(format!("Invalid URL {:?}", raw)))?;
+    let host = url.host_str().unwrap_or_default();
+    if host != "bbc.co.uk" && !host.ends_with(".bbc.co.uk") {
+        return Err(ProxyError::BadRequest(format!(
+            "{:?} is not a bbc.co.uk URL",
+            raw
+        )));
+    }
+    url.path_segments()
+        .and_then(|segments| segments.rev().find(|s| is_pid(s)))
+        .map(String::from)
+        .ok_or_else(|| ProxyError::BadRequest(format!("No programme ID found in {:?}", raw)))
+}
+
+/// `GET /resolve?url=...`: turn a BBC share URL into this proxy's feed URL
+pub fn get_resolve(state: &AppState, req: &Request<Body>) -> Response<Body> {
+    let raw = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(k, _)| k == "url")
+        .map(|(_, v)| v.into_owned());
+    let id = match raw
+        .ok_or_else(|| ProxyError::BadRequest("Missing url parameter".to_string()))
+        .and_then(|raw| extract_pid(&raw))
+    {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let resolved = Resolved {
+        feed_url: format!("{}/{}", base_url(state, req).unwrap_or_default(), id),
+        id,
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&resolved).unwrap()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, text, MockBbc, Upstream};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn pids_come_from_bbc_urls_only() {
+        for (url, pid) in [
+            ("https://www.bbc.co.uk/sounds/play/b006qykl", "b006qykl"),
+            (
+                "https://www.bbc.co.uk/sounds/brand/b006qykl?x=1",
+                "b006qykl",
+            ),
+            ("https://bbc.co.uk/programmes/b006qykl/episodes", "b006qykl"),
+            ("http://www.bbc.co.uk/sounds/series/p0abcdef1/", "p0abcdef1"),
+        ] {
+            assert_eq!(extract_pid(url).unwrap(), pid, "{}", url);
+        }
+        for url in [
+            "https://www.bbc.com.evil.example/sounds/play/b006qykl",
+            "https://evilbbc.co.uk/sounds/play/b006qykl",
+            "https://www.bbc.co.uk/sounds/play/",
+            "https://www.bbc.co.uk/sounds/play/B006QYKL",
+            "https://www.bbc.co.uk/programmes/episodes",
+            "www.bbc.co.uk/sounds/play/b006qykl",
+        ] {
+            assert!(extract_pid(url).is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolved_urls_point_at_this_proxy() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let req =
+            Request::get("/resolve?url=https%3A%2F%2Fwww.bbc.co.uk%2Fsounds%2Fplay%2Fb006qykl")
+                .header(header::HOST, "proxy.example")
+                .body(Body::empty())
+                .unwrap();
+        let response = testing::send(&state, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let json: Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!(
+            json,
+            json!({ "id": "b006qykl", "feed_url": "http://proxy.example/b006qykl" })
+        );
+        for uri in [
+            "/resolve",
+            "/resolve?url=https%3A%2F%2Fexample.com%2Fb006qykl",
+        ] {
+            let response = testing::get(&state, uri).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        assert_eq!(bbc.hits(""), 0);
+    }
+}
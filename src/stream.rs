@@ -0,0 +1,203 @@
+use futures::join;
+use futures::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::{header, Body, Response, StatusCode};
+use std::sync::Arc;
+
+use crate::bbc::{self, PodContainer, PodEpisodes};
+use crate::config::Config;
+use crate::error::ProxyError;
+use crate::options::{FeedOptions, Format};
+use crate::{build_channel, build_item, extensions, is_trailer, matches_filters, AppState};
+
+/// Whether a feed can be streamed a page of episodes at a time. Anything that
+/// needs the whole episode list before the first item is written rules it out.
+pub fn can_stream(config: &Config, opts: &FeedOptions) -> bool {
+    config.stream_feeds
+        && opts.format == Format::Rss
+        && opts.order.is_none()
+        && !opts.pretty
+        && !config.synthesize_episode_numbers
+        && !config.show_total_duration
+        && !config.title_template.uses_episode()
+        && config.max_response_bytes.is_none()
+}
+
+/// A streamed feed's progress through the programme's pages of episodes
+struct Pages {
+    state: Arc<AppState>,
+    id: String,
+    info: Arc<PodContainer>,
+    opts: FeedOptions,
+    audio_base: Option<String>,
+    /// The next page to render, if it has already been fetched
+    next: Option<PodEpisodes>,
+    offset: usize,
+    total: usize,
+    /// Items left before `?limit=` is reached
+    remaining: usize,
+}
+
+impl Pages {
+    /// The `<item>` elements for the episodes of `page` that pass the filters
+    fn render(&mut self, page: &PodEpisodes) -> Bytes {
+        let config = &self.state.config;
+        let now = config.now();
+        let items: Vec<rss::Item> = page
+            .data
+            .iter()
+            .filter(|e| !(config.drop_trailers && is_trailer(e, config)))
+            .filter(|e| matches_filters(e, &self.opts, now))
+            .take(self.remaining)
+            .map(|e| {
+                build_item(
+                    &self.id,
+                    &self.info,
+                    e,
+                    config,
+                    &self.opts,
+                    self.audio_base.as_deref(),
+                )
+            })
+            .collect();
+        self.remaining -= items.len();
+        // The rss crate only writes whole channels, so write the items in an
+        // otherwise empty one and cut them out
+        let xml = rss::Channel {
+            items,
+            ..Default::default()
+        }
+        .to_string();
+        match (xml.find("<item>"), xml.rfind("</channel>")) {
+            (Some(start), Some(end)) => Bytes::copy_from_slice(&xml.as_bytes()[start..end]),
+            _ => Bytes::new(),
+        }
+    }
+
+    /// Fetch and render the next page with any items to show, or return
+    /// `None` once there are no more
+    async fn next_chunk(mut self) -> Option<(Result<Bytes, ProxyError>, Self)> {
+        while self.remaining > 0 {
+            let page = match self.next.take() {
+                Some(page) => page,
+                None if self.offset >= self.total => return None,
+                None => match bbc::episodes_page(&self.state, &self.id, self.offset).await {
+                    Ok(page) if page.data.is_empty() => return None,
+                    Ok(page) => page,
+                    Err(e) if self.state.config.partial_on_pagination_error => {
+                        log::warn!(
+                            "episodes of {} at offset {} failed, ending streamed feed early: {}",
+                            self.id,
+                            self.offset,
+                            e
+                        );
+                        return None;
+                    }
+                    Err(e) => {
+                        // hyper abandons the response on an error, so don't
+                        // fetch any further
+                        self.total = 0;
+                        return Some((Err(e), self));
+                    }
+                },
+            };
+            self.offset += page.data.len();
+            let chunk = self.render(&page);
+            if !chunk.is_empty() {
+                return Some((Ok(chunk), self));
+            }
+        }
+        None
+    }
+}
+
+/// Write a feed as its pages of episodes are fetched, so that only one page
+/// is held in memory at a time. Streamed feeds aren't cached.
+pub async fn stream_feed(
+    state: Arc<AppState>,
+    id: String,
+    opts: FeedOptions,
+    audio_base: Option<String>,
+) -> Result<Response<Body>, ProxyError> {
+    let (info, first) = join!(
+        bbc::container(&state, &id, opts.cache),
+        bbc::episodes_page(&state, &id, 0)
+    );
+    if let (Err(a), Err(b)) = (&info, &first) {
+        return Err(ProxyError::most_informative(a, b).clone());
+    }
+    let info = info?;
+    let first = match first {
+        Err(ProxyError::NotFound(_)) if state.config.empty_on_no_episodes => PodEpisodes::default(),
+        first => first?,
+    };
+    let mut channel = build_channel(
+        &id,
+        &info,
+        &PodEpisodes::default(),
+        &state.config,
+        &opts,
+        audio_base.as_deref(),
+    );
+    // The items aren't known yet, so declare every namespace they might use
+    extensions::declare_all_namespaces(&mut channel);
+    let xml = channel.to_string();
+    let split = xml.rfind("</channel>").unwrap_or(xml.len());
+    let prologue = Bytes::copy_from_slice(&xml.as_bytes()[..split]);
+    let epilogue = Bytes::copy_from_slice(&xml.as_bytes()[split..]);
+    let canonical_id = info.canonical_id.clone();
+    let show_canonical_id = state.config.canonical_id_header;
+    let pages = Pages {
+        total: first.total,
+        next: Some(first),
+        offset: 0,
+        remaining: opts.limit.unwrap_or(usize::MAX),
+        state,
+        id,
+        info,
+        opts,
+        audio_base,
+    };
+    let body = stream::once(async { Ok(prologue) })
+        .chain(stream::unfold(pages, Pages::next_chunk))
+        .chain(stream::once(async { Ok(epilogue) }));
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, Format::Rss.content_type());
+    if let Some(canonical) = canonical_id.filter(|_| show_canonical_id) {
+        builder = builder.header("X-Canonical-Id", canonical);
+    }
+    Ok(builder.body(Body::wrap_stream(body)).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, episode_json, get, text, MockBbc, Upstream, PROGRAMME};
+
+    fn titles(feed: &str) -> Vec<String> {
+        let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+        channel
+            .items
+            .iter()
+            .map(|item| item.title.clone().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streamed_feed_is_well_formed_and_complete() {
+        let bbc = MockBbc::start(Upstream {
+            episodes: (1..=5).map(episode_json).collect(),
+            page_size: 2,
+            ..Upstream::default()
+        })
+        .await;
+        let path = format!("/{}", PROGRAMME);
+        let streamed = text(get(&testing::state(&bbc, &["--stream-feeds"]), &path).await).await;
+        let rendered = text(get(&testing::state(&bbc, &[]), &path).await).await;
+        let streamed = titles(&streamed);
+        assert_eq!(streamed.len(), 5);
+        assert_eq!(streamed, titles(&rendered));
+        // Each of the three pages was fetched by each feed
+        assert_eq!(bbc.hits("offset="), 6);
+    }
+}
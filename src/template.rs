@@ -0,0 +1,146 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Values available to a title template
+pub struct TitleFields<'a> {
+    pub primary: &'a str,
+    pub secondary: &'a str,
+    pub date: &'a str,
+    pub series: &'a str,
+    pub episode: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Field {
+    Primary,
+    Secondary,
+    Date,
+    Series,
+    Episode,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// An item title format such as `{series}: {secondary}`, parsed once at
+/// startup so that unknown placeholders are rejected before serving
+#[derive(Clone, Debug)]
+pub struct TitleTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl FromStr for TitleTemplate {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| TemplateError(format!("unclosed '{{' in {:?}", s)))?;
+            let field = match &rest[start + 1..start + end] {
+                "primary" => Field::Primary,
+                "secondary" => Field::Secondary,
+                "date" => Field::Date,
+                "series" => Field::Series,
+                "episode" => Field::Episode,
+                other => return Err(TemplateError(format!("unknown placeholder {{{}}}", other))),
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(TitleTemplate { parts })
+    }
+}
+
+impl TitleTemplate {
+    /// Whether titles include `{episode}`, which needs the whole episode list
+    /// to number
+    pub fn uses_episode(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Field(Field::Episode)))
+    }
+
+    pub fn render(&self, fields: &TitleFields) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Field(Field::Primary) => out.push_str(fields.primary),
+                Part::Field(Field::Secondary) => out.push_str(fields.secondary),
+                Part::Field(Field::Date) => out.push_str(fields.date),
+                Part::Field(Field::Series) => out.push_str(fields.series),
+                Part::Field(Field::Episode) => out.push_str(&fields.episode.to_string()),
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> String {
+        let fields = TitleFields {
+            primary: "Programme",
+            secondary: "Episode",
+            date: "2024-01-02",
+            series: "Series 1",
+            episode: 7,
+        };
+        template.parse::<TitleTemplate>().unwrap().render(&fields)
+    }
+
+    #[test]
+    fn templates_render_their_fields() {
+        assert_eq!(render("{secondary}"), "Episode");
+        assert_eq!(render("{series}: {secondary}"), "Series 1: Episode");
+        assert_eq!(
+            render("#{episode} {primary} ({date})"),
+            "#7 Programme (2024-01-02)"
+        );
+        assert_eq!(render("no fields"), "no fields");
+        assert_eq!(render(""), "");
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        assert!("{title}".parse::<TitleTemplate>().is_err());
+        assert!("{secondary".parse::<TitleTemplate>().is_err());
+    }
+
+    #[test]
+    fn only_episode_placeholders_need_numbering() {
+        assert!("{episode}. {secondary}"
+            .parse::<TitleTemplate>()
+            .unwrap()
+            .uses_episode());
+        assert!(!"{secondary}"
+            .parse::<TitleTemplate>()
+            .unwrap()
+            .uses_episode());
+    }
+}
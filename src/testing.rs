@@ -0,0 +1,375 @@
+//! Helpers for tests: a stand-in for the BBC API, and requests to a proxy
+//! using it
+
+// Each test uses only some of the helpers
+#![allow(dead_code)]
+
+use clap::Parser;
+use hyper::body::Bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use url::form_urlencoded;
+
+use crate::audio_cache::AudioCache;
+use crate::bbc::{PodContainer, PodEpisode};
+use crate::config::Config;
+use crate::overrides::Overrides;
+use crate::{http_clients, router, AppState};
+
+/// The programme the mock BBC API knows about
+pub const PROGRAMME: &str = "p0000001";
+
+/// Episode `n` of `PROGRAMME`, as BBC describes it, released on day `n` of
+/// 2024 and available until 2030
+pub fn episode_json(n: usize) -> Value {
+    let released = chrono::NaiveDate::from_ymd(2024, 1, 1) + chrono::Duration::days(n as i64 - 1);
+    let variant = |label: &str, bitrate: u32, size: u32| {
+        json!({
+            "bitrate": bitrate,
+            "file_url": format!("https://open.live.bbc.co.uk/{}/{}.mp3", label, n),
+            "file_size": size,
+            "label": label,
+        })
+    };
+    json!({
+        "id": format!("e{:07}", n),
+        "titles": { "primary": "Programme", "secondary": format!("Episode {}", n) },
+        "synopses": {
+            "short": format!("Short {}", n),
+            "medium": format!("Medium {}", n),
+            "long": format!("Long {}", n),
+        },
+        "image_url": "https://ichef.bbci.co.uk/images/ic/{recipe}/e.jpg",
+        "duration": { "value": 60 * n, "label": format!("{} mins", n) },
+        "download": {
+            "type": "non-drm",
+            "quality_variants": {
+                "low": variant("Low", 48, 1_000_000),
+                "medium": variant("Medium", 96, 2_000_000),
+                "high": variant("High", 128, 3_000_000),
+            },
+        },
+        "release": {
+            "date": format!("{}T10:00:00Z", released),
+            "label": released.format("%a %-d %b %Y").to_string(),
+        },
+        "availability": { "from": format!("{}T10:00:00Z", released), "to": "2030-01-01T00:00:00Z" },
+    })
+}
+
+pub fn episode(n: usize) -> PodEpisode {
+    serde_json::from_value(episode_json(n)).unwrap()
+}
+
+/// `PROGRAMME` as the default `Upstream` describes it
+pub fn container() -> PodContainer {
+    serde_json::from_value(Upstream::default().container).unwrap()
+}
+
+/// A canned response for a path on the mock BBC API
+#[derive(Clone)]
+pub struct Raw {
+    pub status: u16,
+    pub content_type: Option<&'static str>,
+    pub body: Vec<u8>,
+    pub location: Option<String>,
+    pub headers: Vec<(&'static str, &'static str)>,
+}
+
+impl Raw {
+    pub fn new(status: u16, content_type: &'static str, body: &str) -> Self {
+        Raw {
+            status,
+            content_type: Some(content_type),
+            body: body.as_bytes().to_vec(),
+            location: None,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        Raw {
+            status: 301,
+            content_type: None,
+            body: Vec::new(),
+            location: Some(location.to_string()),
+            headers: Vec::new(),
+        }
+    }
+}
+
+/// What the mock BBC API serves
+#[derive(Clone)]
+pub struct Upstream {
+    pub container: Value,
+    /// `PROGRAMME`'s episodes, in BBC's order
+    pub episodes: Vec<Value>,
+    /// Episodes of other programmes by ID, which share `container`
+    pub others: HashMap<String, Vec<Value>>,
+    pub page_size: usize,
+    /// Pages from this offset on fail with a 503
+    pub fail_from_offset: Option<usize>,
+    /// Responses for particular paths, without the query string, served
+    /// instead of anything else
+    pub raw: HashMap<String, Raw>,
+    /// Audio files by path, served with support for `Range`
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Upstream {
+            container: json!({
+                "titles": { "primary": "Programme", "secondary": null },
+                "synopses": { "short": "Short", "medium": "Medium", "long": "Long" },
+                "image_url": "https://ichef.bbci.co.uk/images/ic/{recipe}/p.jpg",
+            }),
+            episodes: (1..=3).map(episode_json).collect(),
+            others: HashMap::new(),
+            page_size: 100,
+            fail_from_offset: None,
+            raw: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+}
+
+/// A stand-in for the BBC API on a local port, recording the URL and any
+/// `Range` of the requests it gets. Every request fails with a 503 while
+/// `fail` is set.
+pub struct MockBbc {
+    pub url: String,
+    fail: Arc<AtomicBool>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+/// BBC's response for a programme it doesn't know
+pub fn not_found(id: &str) -> Value {
+    json!({ "errors": [{
+        "id": "not_found",
+        "href": "",
+        "status": 404,
+        "message": format!("Programme {} not found", id),
+        "replied_at": "",
+    }] })
+}
+
+fn respond(upstream: &Upstream, req: &Request<Body>) -> Response<Body> {
+    let path = req.uri().path();
+    if let Some(raw) = upstream.raw.get(path) {
+        let mut builder = Response::builder().status(raw.status);
+        if let Some(content_type) = raw.content_type {
+            builder = builder.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(location) = &raw.location {
+            builder = builder.header(header::LOCATION, location.as_str());
+        }
+        for (name, value) in &raw.headers {
+            builder = builder.header(*name, *value);
+        }
+        return builder.body(Body::from(raw.body.clone())).unwrap();
+    }
+    if let Some(file) = upstream.files.get(path) {
+        return serve_file(file, req.headers().get(header::RANGE));
+    }
+    let query: HashMap<String, String> =
+        form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let body = match segments.as_slice() {
+        [_, "programmes", "playable"] => {
+            let id = query
+                .get("container")
+                .map(String::as_str)
+                .unwrap_or_default();
+            let offset: usize = query
+                .get("offset")
+                .and_then(|o| o.parse().ok())
+                .unwrap_or(0);
+            if upstream.fail_from_offset.is_some_and(|fail| offset >= fail) {
+                return unavailable();
+            }
+            let episodes = match id {
+                PROGRAMME => Some(&upstream.episodes),
+                _ => upstream.others.get(id),
+            };
+            match episodes {
+                Some(episodes) => {
+                    let page: Vec<&Value> = episodes
+                        .iter()
+                        .skip(offset)
+                        .take(upstream.page_size)
+                        .collect();
+                    json!({ "data": page, "total": episodes.len() })
+                }
+                None => not_found(id),
+            }
+        }
+        [_, "programmes", id, "container"]
+            if *id == PROGRAMME || upstream.others.contains_key(*id) =>
+        {
+            upstream.container.clone()
+        }
+        [_, "programmes", id, "container"] => not_found(id),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn serve_file(file: &[u8], range: Option<&header::HeaderValue>) -> Response<Body> {
+    let len = file.len() as u64;
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, "audio/mpeg")
+        .header(header::ACCEPT_RANGES, "bytes");
+    let response = match crate::range::parse_range(range, len) {
+        Ok(Some((start, end))) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            )
+            .body(Body::from(file[start as usize..=end as usize].to_vec())),
+        Ok(None) => builder.body(Body::from(file.to_vec())),
+        Err(()) => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty()),
+    };
+    response.unwrap()
+}
+
+fn unavailable() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from("Service Unavailable"))
+        .unwrap()
+}
+
+impl MockBbc {
+    pub async fn start(upstream: Upstream) -> Self {
+        let upstream = Arc::new(upstream);
+        let fail = Arc::new(AtomicBool::new(false));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let make_service = {
+            let (fail, requests) = (fail.clone(), requests.clone());
+            make_service_fn(move |_| {
+                let (upstream, fail, requests) = (upstream.clone(), fail.clone(), requests.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let range = req
+                            .headers()
+                            .get(header::RANGE)
+                            .map_or("", |r| r.to_str().unwrap_or_default());
+                        let request = format!("{} {}", req.uri(), range);
+                        requests.lock().unwrap().push(request);
+                        let response = if fail.load(Ordering::SeqCst) {
+                            unavailable()
+                        } else {
+                            respond(&upstream, &req)
+                        };
+                        async move { Ok::<_, Infallible>(response) }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        MockBbc {
+            url,
+            fail,
+            requests,
+        }
+    }
+
+    /// How many requests were made for URLs, followed by a space and any
+    /// `Range`, containing `pattern`
+    pub fn hits(&self, pattern: &str) -> usize {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().filter(|url| url.contains(pattern)).count()
+    }
+
+    pub fn set_failing(&self, fail: bool) {
+        self.fail.store(fail, Ordering::SeqCst);
+    }
+}
+
+/// A proxy using `bbc`, configured by command-line `args`
+pub fn state(bbc: &MockBbc, args: &[&str]) -> Arc<AppState> {
+    state_with_audio_cache(bbc, args, None)
+}
+
+pub fn state_with_audio_cache(
+    bbc: &MockBbc,
+    args: &[&str],
+    audio_cache: Option<Arc<AudioCache>>,
+) -> Arc<AppState> {
+    let mut argv = vec!["soundsproxy", "--api-base-url", &bbc.url];
+    argv.extend_from_slice(args);
+    let config = Config::try_parse_from(argv).unwrap();
+    let (client, audio_client) = http_clients(&config).unwrap();
+    Arc::new(AppState::new(
+        config,
+        client,
+        audio_client,
+        audio_cache,
+        Overrides::new(),
+    ))
+}
+
+/// Send `req` to the proxy, from a client at 127.0.0.1
+pub async fn send(state: &Arc<AppState>, req: Request<Body>) -> Response<Body> {
+    let remote = SocketAddr::from(([127, 0, 0, 1], 40000));
+    router(req, state.clone(), remote).await.unwrap()
+}
+
+pub async fn get(state: &Arc<AppState>, uri: &str) -> Response<Body> {
+    send(state, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+pub async fn bytes(response: Response<Body>) -> Bytes {
+    hyper::body::to_bytes(response.into_body()).await.unwrap()
+}
+
+pub async fn text(response: Response<Body>) -> String {
+    String::from_utf8(bytes(response).await.to_vec()).unwrap()
+}
+
+/// The value of header `name`, or "" if it's missing
+pub fn header<'a>(response: &'a Response<Body>, name: &str) -> &'a str {
+    response
+        .headers()
+        .get(name)
+        .map_or("", |v| v.to_str().unwrap())
+}
+
+/// A new directory path under the system's temporary directory, for a test
+/// to create and remove
+pub fn tempdir() -> PathBuf {
+    use std::sync::atomic::AtomicUsize;
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "soundsproxy-test-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
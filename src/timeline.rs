@@ -0,0 +1,307 @@
+use futures::join;
+use futures::stream::{self, StreamExt};
+use hyper::{header, Body, Request, Response, StatusCode};
+use std::cmp::Reverse;
+use std::sync::Arc;
+use url::form_urlencoded;
+
+use crate::bbc::{self, PodContainer, PodEpisode, PodEpisodes, PodSynopses, PodTitles};
+use crate::cache::CachePolicy;
+use crate::error::ProxyError;
+use crate::options::{parse_bool, FeedOptions, Format};
+use crate::validate::query_ids;
+use crate::{
+    audio_base, base_url, build_channel, build_item, check_id, extensions, is_trailer,
+    matches_filters, number_episodes, release_date, write_channel, AppState,
+};
+
+/// Most programmes one timeline may combine
+const MAX_PROGRAMMES: usize = 20;
+
+/// Programmes to fetch at once
+const CONCURRENCY: usize = 4;
+
+/// Episodes in a timeline without `?limit=`
+const DEFAULT_LIMIT: usize = 50;
+
+const DEFAULT_TITLE: &str = "BBC Sounds timeline";
+
+struct Programme {
+    id: String,
+    info: Arc<PodContainer>,
+    episodes: Vec<PodEpisode>,
+}
+
+async fn programme(
+    state: &AppState,
+    id: String,
+    policy: CachePolicy,
+) -> Result<Programme, ProxyError> {
+    check_id(state, &id)?;
+    let (info, episodes) = join!(
+        bbc::container(state, &id, policy),
+        bbc::episodes(state, &id, policy)
+    );
+    if let (Err(a), Err(b)) = (&info, &episodes) {
+        return Err(ProxyError::most_informative(a, b).clone());
+    }
+    let mut episodes = episodes?.data.clone();
+    if state.config.drop_trailers {
+        episodes.retain(|e| !is_trailer(e, &state.config));
+    }
+    number_episodes(&mut episodes);
+    Ok(Programme {
+        id,
+        info: info?,
+        episodes,
+    })
+}
+
+/// `GET /timeline?ids=a,b,c`: the latest episodes across several programmes,
+/// newest first, as one feed. Takes `?title=` and the usual feed parameters,
+/// though `?limit=` defaults to 50 and `?order=` is ignored. Programmes that
+/// fail to load are left out, or with `?strict=true` fail the whole request.
+pub async fn get_timeline(state: &AppState, req: &Request<Body>) -> Response<Body> {
+    let ids = query_ids(req);
+    if ids.is_empty() {
+        return ProxyError::BadRequest("Missing ids parameter".to_string()).into_response();
+    }
+    if ids.len() > MAX_PROGRAMMES {
+        return ProxyError::BadRequest(format!(
+            "At most {} programmes may be combined",
+            MAX_PROGRAMMES
+        ))
+        .into_response();
+    }
+    let opts = match FeedOptions::from_query(req.uri().query()) {
+        Ok(opts) => opts,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = opts.check_image_size(&state.config.image_recipes) {
+        return e.into_response();
+    }
+    let mut title = DEFAULT_TITLE.to_string();
+    let mut strict = false;
+    for (key, value) in form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "title" => title = value.into_owned(),
+            "strict" => match parse_bool(&key, &value) {
+                Ok(value) => strict = value,
+                Err(e) => return e.into_response(),
+            },
+            _ => {}
+        }
+    }
+    let base = base_url(state, req);
+    let audio_base = audio_base(state, req);
+
+    let results: Vec<(String, Result<Programme, ProxyError>)> = stream::iter(ids)
+        .map(|id| async move {
+            let result = programme(state, id.clone(), opts.cache).await;
+            (id, result)
+        })
+        .buffered(CONCURRENCY)
+        .collect()
+        .await;
+    let mut programmes = Vec::new();
+    let mut failed = Vec::new();
+    for (id, result) in results {
+        match result {
+            Ok(programme) => programmes.push(programme),
+            Err(e) => failed.push((id, e)),
+        }
+    }
+    if strict && !failed.is_empty() {
+        // Any upstream failure outranks bad or missing IDs
+        let status = failed.iter().map(|(_, e)| e.status()).max().unwrap();
+        let reasons: Vec<String> = failed
+            .iter()
+            .map(|(id, e)| format!("{}: {}", id, e))
+            .collect();
+        return Response::builder()
+            .status(status)
+            .body(Body::from(format!(
+                "Failed to load programmes\n{}",
+                reasons.join("\n")
+            )))
+            .unwrap();
+    }
+    for (id, e) in &failed {
+        log::warn!("leaving {} out of timeline: {}", id, e);
+    }
+
+    let now = state.config.now();
+    let mut episodes: Vec<(&Programme, &PodEpisode)> = programmes
+        .iter()
+        .flat_map(|p| p.episodes.iter().map(move |e| (p, e)))
+        .filter(|(_, e)| matches_filters(e, &opts, now))
+        .collect();
+    episodes.sort_by_key(|(_, e)| Reverse(release_date(e)));
+    episodes.truncate(opts.limit.unwrap_or(DEFAULT_LIMIT));
+
+    let items: Vec<rss::Item> = episodes
+        .into_iter()
+        .map(|(p, e)| {
+            let mut item = build_item(
+                &p.id,
+                &p.info,
+                e,
+                &state.config,
+                &opts,
+                audio_base.as_deref(),
+            );
+            // Say which programme each episode is from
+            item.title = item
+                .title
+                .map(|title| format!("{}: {}", p.info.titles.primary, title));
+            // Keep GUIDs unique across programmes, whatever `--guid-source`
+            if let Some(guid) = &mut item.guid {
+                guid.value = format!("{}:{}", p.id, guid.value);
+            }
+            item
+        })
+        .collect();
+
+    let names: Vec<&str> = programmes
+        .iter()
+        .map(|p| p.info.titles.primary.as_str())
+        .collect();
+    let description = format!("The latest episodes of {}", names.join(", "));
+    let info = PodContainer {
+        titles: PodTitles {
+            primary: title,
+            secondary: String::new(),
+        },
+        synopses: PodSynopses {
+            short: description.clone(),
+            medium: description.clone(),
+            long: description.clone(),
+        },
+        image_url: String::new(),
+        contributors: Vec::new(),
+        canonical_id: None,
+    };
+    let mut channel = build_channel(
+        "",
+        &info,
+        &PodEpisodes::default(),
+        &state.config,
+        &opts,
+        audio_base.as_deref(),
+    );
+    channel.description = description;
+    channel.link = match &base {
+        // An HTTP/2 request's URI is absolute, so only its path is added
+        Some(base) => {
+            let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+            format!("{}{}", base, path)
+        }
+        None => "https://www.bbc.co.uk/sounds".to_string(),
+    };
+    channel.items = items;
+    extensions::declare_namespaces(&mut channel);
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, Format::Rss.content_type())
+        .body(Body::from(write_channel(&channel, opts.pretty)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, get, text, MockBbc, Raw, Upstream};
+    use hyper::{Body, Request, StatusCode};
+
+    #[tokio::test]
+    async fn link_has_the_path_of_an_absolute_uri() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let req = Request::get("http://proxy.example:8223/timeline?ids=p0000001")
+            .body(Body::empty())
+            .unwrap();
+        let feed = text(testing::send(&state, req).await).await;
+        assert!(
+            feed.contains("<link>http://proxy.example:8223/timeline?ids=p0000001</link>"),
+            "{}",
+            feed
+        );
+    }
+
+    #[tokio::test]
+    async fn strict_fails_on_any_programme_that_does_not_load() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let timeline = |query: &'static str| {
+            let state = state.clone();
+            async move { get(&state, &format!("/timeline?{}", query)).await }
+        };
+
+        // By default the missing programme is left out
+        let response = timeline("ids=p0000001,p0000404").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(text(response).await.matches("<item>").count(), 3);
+
+        let response = timeline("ids=p0000001,p0000404&strict=true").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = text(response).await;
+        assert!(body.contains("p0000404: "), "{}", body);
+        assert!(!body.contains("p0000001"), "{}", body);
+
+        let response = timeline("ids=p0000001&strict=true").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = timeline("ids=p0000001&strict=maybe").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn strict_reports_bbc_failing_over_a_missing_programme() {
+        let mut upstream = Upstream::default();
+        upstream.raw.insert(
+            "/v2/programmes/playable".to_string(),
+            Raw::new(503, "text/plain", "Service Unavailable"),
+        );
+        let bbc = MockBbc::start(upstream).await;
+        let state = testing::state(&bbc, &[]);
+        let response = get(&state, "/timeline?ids=p0000404,p0000001&strict=true").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = text(response).await;
+        assert!(
+            body.contains("p0000404: ") && body.contains("p0000001: "),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn guids_are_unique_across_programmes() {
+        // The same episode under two programmes
+        let mut upstream = Upstream::default();
+        upstream
+            .others
+            .insert("p0000002".to_string(), vec![testing::episode_json(1)]);
+        let bbc = MockBbc::start(upstream).await;
+        let guids = |args: &'static [&'static str], path: &'static str| {
+            let state = testing::state(&bbc, args);
+            async move {
+                let feed = text(get(&state, path).await).await;
+                let channel = rss::Channel::read_from(feed.as_bytes()).unwrap();
+                channel
+                    .items
+                    .iter()
+                    .map(|i| i.guid.clone().unwrap().value)
+                    .collect::<Vec<_>>()
+            }
+        };
+        for args in [&[][..], &["--guid-source", "url"]] {
+            let single = guids(args, "/p0000002").await;
+            let timeline = guids(args, "/timeline?ids=p0000001,p0000002").await;
+            assert_eq!(timeline.len(), 4);
+            let unique: std::collections::HashSet<&String> = timeline.iter().collect();
+            assert_eq!(unique.len(), 4, "{:?}", timeline);
+            // Single-programme feeds aren't prefixed
+            assert!(timeline.contains(&format!("p0000002:{}", single[0])));
+            assert!(timeline.contains(&format!("p0000001:{}", single[0])));
+        }
+    }
+}
@@ -0,0 +1,81 @@
+/// Words left lowercase unless they start the title or a phrase within it,
+/// such as after a colon or dash
+const SMALL_WORDS: [&str; 18] = [
+    "a", "an", "and", "as", "at", "but", "by", "for", "in", "nor", "of", "on", "or", "the", "to",
+    "via", "vs", "with",
+];
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Convert `title` to title case. Words matching one of `acronyms`
+/// (case-insensitively) take the acronym's spelling, and mixed-case words
+/// such as "iPlayer" are left alone.
+pub fn title_case(title: &str, acronyms: &[String]) -> String {
+    let mut out = Vec::new();
+    let mut start_of_phrase = true;
+    for word in title.split(' ') {
+        let core = word.trim_matches(|c: char| !c.is_alphanumeric());
+        let has_upper = core.chars().any(char::is_uppercase);
+        let has_lower = core.chars().any(char::is_lowercase);
+        let converted =
+            if let Some(acronym) = acronyms.iter().find(|a| a.eq_ignore_ascii_case(core)) {
+                word.replacen(core, acronym, 1)
+            } else if core.is_empty() || (has_upper && has_lower) {
+                word.to_string()
+            } else {
+                let lower = word.to_lowercase();
+                if !start_of_phrase && SMALL_WORDS.contains(&core.to_lowercase().as_str()) {
+                    lower
+                } else {
+                    let offset = lower.find(|c: char| c.is_alphanumeric()).unwrap_or(0);
+                    format!("{}{}", &lower[..offset], capitalize(&lower[offset..]))
+                }
+            };
+        if core.is_empty() {
+            start_of_phrase |= word == "-" || word == "–";
+        } else {
+            start_of_phrase = word.ends_with([':', '?', '!']);
+        }
+        out.push(converted);
+    }
+    out.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(title: &str) -> String {
+        let acronyms: Vec<String> = ["BBC", "UK", "NHS"].map(String::from).to_vec();
+        title_case(title, &acronyms)
+    }
+
+    #[test]
+    fn acronyms_keep_their_spelling() {
+        assert_eq!(convert("THE STORY OF THE BBC"), "The Story of the BBC");
+        assert_eq!(convert("nhs at 75"), "NHS at 75");
+        assert_eq!(convert("Made in the uk."), "Made in the UK.");
+    }
+
+    #[test]
+    fn small_words_stay_lowercase_within_a_phrase() {
+        assert_eq!(convert("war and peace"), "War and Peace");
+        assert_eq!(convert("the end of the line"), "The End of the Line");
+        assert_eq!(convert("history: a beginning"), "History: A Beginning");
+        assert_eq!(convert("news - in brief"), "News - In Brief");
+    }
+
+    #[test]
+    fn mixed_case_and_punctuated_words() {
+        assert_eq!(convert("my iPlayer tips"), "My iPlayer Tips");
+        assert_eq!(convert("\"quoted words\""), "\"Quoted Words\"");
+        assert_eq!(convert("episode 12"), "Episode 12");
+        assert_eq!(convert(""), "");
+    }
+}
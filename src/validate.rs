@@ -0,0 +1,161 @@
+use futures::stream::{self, StreamExt};
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use url::form_urlencoded;
+
+use crate::bbc;
+use crate::cache::CachePolicy;
+use crate::error::ProxyError;
+use crate::{check_id, AppState};
+
+/// Most IDs one `/validate-ids` request may check
+const MAX_IDS: usize = 50;
+
+/// Container fetches to run at once
+const CONCURRENCY: usize = 4;
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Valid,
+    NotFound,
+    Error,
+    /// Not a well-formed programme ID, so BBC wasn't asked
+    Invalid,
+    /// Blocked by `--allow-ids` or `--deny-ids`
+    Forbidden,
+}
+
+#[derive(Serialize)]
+struct Validation {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+}
+
+async fn validate(state: &AppState, id: &str) -> Validation {
+    let result = match check_id(state, id) {
+        Ok(()) => bbc::container(state, id, CachePolicy::Normal).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(info) => Validation {
+            status: Status::Valid,
+            title: Some(info.titles.primary.clone()),
+        },
+        Err(e) => Validation {
+            status: match e {
+                ProxyError::NotFound(_) => Status::NotFound,
+                ProxyError::BadRequest(_) => Status::Invalid,
+                ProxyError::Forbidden(_) => Status::Forbidden,
+                _ => Status::Error,
+            },
+            title: None,
+        },
+    }
+}
+
+/// The programme IDs in a request's comma-separated `?ids=`
+pub fn query_ids(req: &Request<Body>) -> Vec<String> {
+    form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .filter(|(k, _)| k == "ids")
+        .flat_map(|(_, v)| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// `GET /validate-ids?ids=a,b,c`: check which programme IDs exist
+pub async fn get_validate_ids(state: &AppState, req: &Request<Body>) -> Response<Body> {
+    let ids = query_ids(req);
+    if ids.is_empty() {
+        return ProxyError::BadRequest("Missing ids parameter".to_string()).into_response();
+    }
+    if ids.len() > MAX_IDS {
+        return ProxyError::BadRequest(format!("At most {} ids may be validated at once", MAX_IDS))
+            .into_response();
+    }
+    let results: BTreeMap<String, Validation> = stream::iter(ids)
+        .map(|id| async move {
+            let validation = validate(state, &id).await;
+            (id, validation)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&results).unwrap()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use serde_json::Value;
+
+    use crate::testing::{self, get, text, MockBbc, Upstream, PROGRAMME};
+
+    #[tokio::test]
+    async fn malformed_and_blocked_ids_are_not_fetched() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &["--deny-ids", "p0000002"]);
+        let long = "a".repeat(2000);
+        let uri = format!(
+            "/validate-ids?ids={},..%2F..%2Fx,a%2Fb,%252e%252e,p0000002,p0000003,{}",
+            PROGRAMME, long
+        );
+        let json: Value = serde_json::from_str(&text(get(&state, &uri).await).await).unwrap();
+        assert_eq!(json[PROGRAMME]["status"], "valid");
+        assert_eq!(json[PROGRAMME]["title"], "Programme");
+        assert_eq!(json["p0000003"]["status"], "not_found");
+        assert_eq!(json["p0000002"]["status"], "forbidden");
+        for id in ["../../x", "a/b", "%2e%2e", long.as_str()] {
+            assert_eq!(json[id]["status"], "invalid", "{}", id);
+        }
+        // Only the two well-formed, allowed IDs reached BBC
+        assert_eq!(bbc.hits("/container"), 2);
+    }
+
+    #[tokio::test]
+    async fn validations_are_capped_cached_and_report_bbc_errors() {
+        let bbc = MockBbc::start(Upstream::default()).await;
+        let state = testing::state(&bbc, &[]);
+        let ids: Vec<String> = (0..51).map(|i| format!("p{:07}", i)).collect();
+        for uri in [
+            "/validate-ids".to_string(),
+            "/validate-ids?ids=,%20,".to_string(),
+            format!("/validate-ids?ids={}", ids.join(",")),
+        ] {
+            let response = get(&state, &uri).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        assert_eq!(bbc.hits(""), 0);
+
+        // Fifty IDs, naming PROGRAMME twice
+        let uri = format!(
+            "/validate-ids?ids={}&ids={}",
+            PROGRAMME,
+            ids[..49].join(",")
+        );
+        let json: Value = serde_json::from_str(&text(get(&state, &uri).await).await).unwrap();
+        assert_eq!(json.as_object().unwrap().len(), 49);
+        assert_eq!(json[PROGRAMME]["status"], "valid");
+        // Checked once, however often it's named, and then cached
+        assert_eq!(bbc.hits(&format!("/{}/container", PROGRAMME)), 1);
+        get(&state, &format!("/validate-ids?ids={}", PROGRAMME)).await;
+        assert_eq!(bbc.hits(&format!("/{}/container", PROGRAMME)), 1);
+
+        bbc.set_failing(true);
+        let uri = "/validate-ids?ids=p0000002";
+        let json: Value = serde_json::from_str(&text(get(&state, uri).await).await).unwrap();
+        assert_eq!(json["p0000002"]["status"], "error");
+        assert!(json["p0000002"].get("title").is_none());
+    }
+}